@@ -4,23 +4,95 @@ use std::fmt;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Broad category of an error, so callers can handle errors differently
+/// without matching on messages
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// Reading or writing a file (or other I/O) failed
+    Io,
+    /// An input file (e.g., subtitles or a blocklist) couldn't be parsed
+    Parse,
+    /// A model file is invalid, or a model can't be used for an operation
+    Model,
+    /// ffmpeg is missing, or failed
+    Ffmpeg,
+    /// Invalid options or arguments
+    Config,
+    /// Generated text couldn't satisfy the given constraints (start token,
+    /// length, blocklist)
+    Constraint,
+    /// None of the input files could be used (e.g., none of the training
+    /// files, or a video without subtitle streams)
+    NoInput,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Io => "io",
+            ErrorKind::Parse => "parse",
+            ErrorKind::Model => "model",
+            ErrorKind::Ffmpeg => "ffmpeg",
+            ErrorKind::Config => "config",
+            ErrorKind::Constraint => "constraint",
+            ErrorKind::NoInput => "no_input",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    /// Process exit code for this kind of error (documented in the README)
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Io => 3,
+            ErrorKind::Parse => 4,
+            ErrorKind::Model => 5,
+            ErrorKind::Ffmpeg => 6,
+            ErrorKind::Config => 7,
+            ErrorKind::Constraint => 8,
+            ErrorKind::NoInput => 9,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug)]
 pub struct Error {
+    pub kind: ErrorKind,
     pub context: Cow<'static, str>,
     pub cause: Option<Box<StdError + 'static>>,
 }
 
 impl Error {
-    pub fn context<S>(text: S) -> Self
+    pub fn new<S>(kind: ErrorKind, text: S) -> Self
     where
         S: Into<Cow<'static, str>>,
     {
         Error {
+            kind,
             context: text.into(),
             cause: None,
         }
     }
 
+    /// Error of kind `Other`
+    pub fn context<S>(text: S) -> Self
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        Self::new(ErrorKind::Other, text)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
     pub fn iter(&self) -> ErrorIter {
         ErrorIter::new(self)
     }
@@ -64,10 +136,18 @@ impl<'a> Iterator for ErrorIter<'a> {
 }
 
 pub trait ResultExt<T> {
+    /// Wraps the error with a message. The kind is taken from the cause if it's
+    /// also an `Error`, or is `Io` for I/O errors, and `Other` otherwise.
     fn context<F, S>(self, to_message: F) -> Result<T>
     where
         F: FnOnce() -> S,
         S: Into<Cow<'static, str>>;
+
+    /// Wraps the error with a message and a specific kind
+    fn context_kind<F, S>(self, kind: ErrorKind, to_message: F) -> Result<T>
+    where
+        F: FnOnce() -> S,
+        S: Into<Cow<'static, str>>;
 }
 
 impl<T, E> ResultExt<T> for std::result::Result<T, E>
@@ -75,13 +155,61 @@ where
     E: StdError + 'static,
 {
     fn context<F, S>(self, to_message: F) -> Result<T>
+    where
+        F: FnOnce() -> S,
+        S: Into<Cow<'static, str>>,
+    {
+        self.map_err(|e| {
+            let kind = {
+                let cause: &(dyn StdError + 'static) = &e;
+                if let Some(err) = cause.downcast_ref::<Error>() {
+                    err.kind
+                } else if cause.is::<std::io::Error>() {
+                    ErrorKind::Io
+                } else {
+                    ErrorKind::Other
+                }
+            };
+
+            Error {
+                kind,
+                context: to_message().into(),
+                cause: Some(e.into()),
+            }
+        })
+    }
+
+    fn context_kind<F, S>(self, kind: ErrorKind, to_message: F) -> Result<T>
     where
         F: FnOnce() -> S,
         S: Into<Cow<'static, str>>,
     {
         self.map_err(|e| Error {
+            kind,
             context: to_message().into(),
             cause: Some(e.into()),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        let kinds = [
+            (ErrorKind::Other, 1),
+            (ErrorKind::Io, 3),
+            (ErrorKind::Parse, 4),
+            (ErrorKind::Model, 5),
+            (ErrorKind::Ffmpeg, 6),
+            (ErrorKind::Config, 7),
+            (ErrorKind::Constraint, 8),
+            (ErrorKind::NoInput, 9),
+        ];
+        for &(kind, code) in &kinds {
+            assert_eq!(kind.exit_code(), code, "{}", kind);
+        }
+    }
+}
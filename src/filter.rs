@@ -0,0 +1,202 @@
+use crate::error::*;
+use crate::model::Model;
+use crate::observer::Observer;
+use regex::{Regex, RegexBuilder};
+use std::collections::HashSet;
+
+/// Default for `ContentFilter::max_retries`
+pub const DEFAULT_MAX_RETRIES: usize = 10;
+
+/// Rejects generated lines that contain blocked words or match blocked patterns
+#[derive(Clone, Debug)]
+pub struct ContentFilter {
+    /// Lowercase words, compared against each token
+    words: HashSet<String>,
+    /// Lowercase phrases of several words, compared against the normalized text
+    phrases: Vec<String>,
+    /// Case-insensitive patterns, compared against the text
+    patterns: Vec<Regex>,
+    /// Number of times a blocked line is regenerated before giving up
+    pub max_retries: usize,
+}
+
+impl Default for ContentFilter {
+    fn default() -> Self {
+        ContentFilter {
+            words: HashSet::new(),
+            phrases: Vec::new(),
+            patterns: Vec::new(),
+            max_retries: DEFAULT_MAX_RETRIES,
+        }
+    }
+}
+
+impl ContentFilter {
+    /// Parses a blocklist with one entry per line. Entries surrounded by
+    /// slashes (e.g., `/fo+/`) are regular expressions, and anything else is a
+    /// word or a phrase of several words. Every entry is case-insensitive
+    /// (patterns can opt out with `(?-i)`). Empty lines and lines starting with
+    /// `#` are ignored.
+    pub fn parse(blocklist: &str) -> Result<Self> {
+        let mut filter = ContentFilter::default();
+
+        for line in blocklist.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.len() > 1 && line.starts_with('/') && line.ends_with('/') {
+                let pattern = RegexBuilder::new(&line[1..line.len() - 1])
+                    .case_insensitive(true)
+                    .build()
+                    .context_kind(ErrorKind::Parse, || {
+                        format!("invalid blocklist pattern: {}", line)
+                    })?;
+                filter.patterns.push(pattern);
+            } else if line.contains(char::is_whitespace) {
+                filter.phrases.push(normalize(line));
+            } else {
+                filter.words.insert(line.to_lowercase());
+            }
+        }
+
+        Ok(filter)
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).context(|| "failed to read blocklist")?;
+        Self::parse(&contents)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty() && self.phrases.is_empty() && self.patterns.is_empty()
+    }
+
+    /// Whether a single token is a blocked word or matches a blocked pattern.
+    /// Phrases aren't checked, since they can't be blocked one token at a time.
+    pub fn is_blocked_token(&self, token: &str) -> bool {
+        self.words.contains(&token.to_lowercase())
+            || self.patterns.iter().any(|pattern| pattern.is_match(token))
+    }
+
+    /// Whether any of the `text`'s tokens is a blocked word, the text contains
+    /// a blocked phrase, or the text matches a blocked pattern
+    pub fn is_blocked(&self, text: &str, tokens: &[String]) -> bool {
+        tokens
+            .iter()
+            .any(|token| self.words.contains(&token.to_lowercase()))
+            || self.contains_phrase(text)
+            || self.patterns.iter().any(|pattern| pattern.is_match(text))
+    }
+
+    fn contains_phrase(&self, text: &str) -> bool {
+        if self.phrases.is_empty() {
+            return false;
+        }
+
+        // Pad with spaces so phrases only match whole words
+        let text = format!(" {} ", normalize(text));
+        self.phrases
+            .iter()
+            .any(|phrase| text.contains(&format!(" {} ", phrase)))
+    }
+
+    /// Removes blocked tokens from the model, so they can't be generated at all
+    pub fn apply_to_model(&self, observer: &dyn Observer, model: &mut Model) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        match model.remove_tokens(|token| self.is_blocked_token(token))? {
+            Some(stats) => observer.info(
+                "Removed blocked tokens from model",
+                &[
+                    ("states_removed", &stats.states_removed),
+                    ("transitions_removed", &stats.transitions_removed),
+                    ("dead_ends_repaired", &stats.dead_ends_repaired),
+                ],
+            ),
+            None => observer.warn(
+                "Blocked tokens can't be removed from mapped models",
+                &[(
+                    "reason",
+                    &"mapped models are read-only, so only generated lines will be filtered",
+                )],
+            ),
+        }
+
+        Ok(())
+    }
+}
+
+/// Lowercases `text` and replaces everything but letters and numbers with
+/// single spaces, so phrases match regardless of punctuation and spacing
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Loads the blocklist at `path` (if any), with the given retry limit
+pub fn load_filter(
+    observer: &dyn Observer,
+    path: Option<&str>,
+    max_retries: usize,
+) -> Result<ContentFilter> {
+    let mut filter = match path {
+        Some(path) => {
+            observer.info("Loading blocklist from file", &[("path", &path)]);
+            ContentFilter::load(path)?
+        }
+        None => ContentFilter::default(),
+    };
+
+    filter.max_retries = max_retries;
+    Ok(filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_blocked(filter: &ContentFilter, text: &str) -> bool {
+        filter.is_blocked(text, &crate::train::tokenize(text))
+    }
+
+    #[test]
+    fn words_match_whole_tokens() {
+        let filter = ContentFilter::parse("# comment\n\nBad\n").unwrap();
+        assert!(is_blocked(&filter, "That's bad!"));
+        assert!(is_blocked(&filter, "BAD"));
+        assert!(!is_blocked(&filter, "Badger"));
+        assert!(!is_blocked(&filter, "# comment"));
+        assert!(filter.is_blocked_token("bAd"));
+    }
+
+    #[test]
+    fn phrases_match_normalized_text() {
+        let filter = ContentFilter::parse("bad  phrase\ndon't go").unwrap();
+        assert!(is_blocked(&filter, "What a Bad... phrase!"));
+        assert!(is_blocked(&filter, "Please, don't go"));
+        assert!(!is_blocked(&filter, "bad phrases"));
+        assert!(!is_blocked(&filter, "phrase bad"));
+        assert!(!filter.is_blocked_token("bad"));
+    }
+
+    #[test]
+    fn patterns_are_case_insensitive() {
+        let filter = ContentFilter::parse("/fo+/\n/(?-i)Bar/").unwrap();
+        assert!(is_blocked(&filter, "FOOD"));
+        assert!(is_blocked(&filter, "Bar"));
+        assert!(!is_blocked(&filter, "bar"));
+        assert!(filter.is_blocked_token("Foo"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let error = ContentFilter::parse("/(/").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Parse);
+    }
+}
@@ -1,78 +1,388 @@
 use crate::error::*;
+use crate::filter::{load_filter, ContentFilter};
+use crate::model::Model;
+use crate::observer::Observer;
 use crate::opts;
-use crate::train::tokenize;
+use crate::score::{GeneratedChain, Score};
 use lazy_static::lazy_static;
-use markov::Chain;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
 use regex::Regex;
-use slog::Logger;
+use serde_derive::Serialize;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::io::Write;
+use std::sync::Arc;
 use subparse::{GenericSubtitleFile, SubtitleFile};
 
+/// Loads the model at `path` and the blocklist, applying the `--min-support`
+/// and `--remove-blocked-tokens` options
+pub fn load_model_from_opts(
+    observer: &dyn Observer,
+    path: &str,
+    opts: &opts::GenerationOpts,
+) -> Result<(Model, ContentFilter)> {
+    observer.info("Loading model from file", &[("path", &path)]);
+    let mut model = crate::load_model(path)?;
+    model.set_min_support(opts.min_support);
+
+    let filter = load_filter(
+        observer,
+        opts.blocklist.as_ref().map(|s| s.as_ref()),
+        opts.max_retries,
+    )?;
+    if opts.remove_blocked_tokens {
+        filter.apply_to_model(observer, &mut model)?;
+    }
+
+    Ok((model, filter))
+}
+
+/// Loads the model at `path` and the blocklist, and returns a builder with the
+/// rest of the generation options set
+pub fn builder_from_opts(
+    observer: &dyn Observer,
+    path: &str,
+    opts: &opts::GenerationOpts,
+) -> Result<GeneratorBuilder> {
+    let (model, filter) = load_model_from_opts(observer, path, opts)?;
+
+    Ok(Generator::builder()
+        .model(model)
+        .min_length(opts.min_length)
+        .temperature(opts.temperature)
+        .candidates(opts.candidates)
+        .filter(filter))
+}
+
 pub fn generate_from_opts(
-    log: &Logger,
+    observer: &dyn Observer,
     args: opts::Generate,
     output: &mut impl Write,
 ) -> Result<()> {
     let subtitle_file = match args.existing_subs {
         None => None,
         Some(path) => {
-            slog::info!(log, "Loading subtitles from file"; "path" => &path);
+            observer.info("Loading subtitles from file", &[("path", &path)]);
             Some(crate::train::get_subtitles_from_file(&path, true)?)
         }
     };
 
-    slog::info!(log, "Loading model from file"; "path" => &args.model);
-    let chain = load_model(&args.model)?;
-
-    let start = args.start.as_ref().map(|s| s.as_ref());
+    let mut generator = builder_from_opts(observer, &args.model, &args.generation)?
+        .start_token(args.start)
+        .seed(args.seed)
+        .build()?;
 
     if let Some(mut file) = subtitle_file {
-        generate_subtitle_file(&log, &mut file, chain, start, args.min_length)?;
+        generator.generate_subtitle_file(&mut file)?;
 
         let data = file
             .to_data()
-            .context(|| "failed to serialize subtitle data")?;
+            .context_kind(ErrorKind::Parse, || "failed to serialize subtitle data")?;
 
         output
             .write(&data)
             .context(|| "failed to write to output")?;
     } else {
-        let lines = generate_lines(&log, chain, start, args.min_length);
-        for line in lines.take(args.count) {
-            output
-                .write(line?.as_ref())
-                .context(|| "failed to write to output")?;
+        let lines = generator.lines().take(args.count);
+
+        match args.output_format {
+            opts::OutputFormat::Text => {
+                for line in lines {
+                    writeln!(output, "{}", line?.text).context(|| "failed to write to output")?;
+                }
+            }
+            opts::OutputFormat::Jsonl => {
+                for line in lines {
+                    serde_json::to_writer(&mut *output, &line?)
+                        .context_kind(ErrorKind::Io, || "failed to serialize generated line")?;
+                    writeln!(output).context(|| "failed to write to output")?;
+                }
+            }
+            opts::OutputFormat::Json => {
+                let lines = lines.collect::<Result<Vec<GeneratedLine>>>()?;
+                serde_json::to_writer_pretty(&mut *output, &lines)
+                    .context_kind(ErrorKind::Io, || "failed to serialize generated lines")?;
+                writeln!(output).context(|| "failed to write to output")?;
+            }
         }
     }
 
     Ok(())
 }
 
-pub fn load_model(path: &str) -> Result<Chain<String>> {
-    Chain::load(path).context(|| "failed to load model file")
-}
-
 lazy_static! {
     // These escape codes highly suggest the line was used for typesetting
     // backgrounds/signs rather than dialogue
     static ref TYPESETTING: Regex = Regex::new(r#"(\\pos|\\blur|\\clip)"#).unwrap();
 }
 
-pub fn generate_subtitle_file(
-    log: &Logger,
-    subtitle_file: &mut GenericSubtitleFile,
-    chain: Chain<String>,
-    start: Option<&str>,
+/// Number of times a line longer than `max_length` is regenerated before giving up
+const MAX_LENGTH_ATTEMPTS: usize = 100;
+
+/// Temperatures can't be negative. See `GeneratorBuilder::temperature`.
+pub fn check_temperature(temperature: f64) -> Result<f64> {
+    if temperature >= 0.0 && temperature.is_finite() {
+        Ok(temperature)
+    } else {
+        Err(Error::new(
+            ErrorKind::Config,
+            "temperature must be a number greater than or equal to 0",
+        ))
+    }
+}
+
+/// Splits a line of text into tokens, the same way as when the model was trained
+pub type Tokenizer = fn(&str) -> Vec<String>;
+
+/// Generated line, along with what's needed to reproduce it
+#[derive(Clone, Debug, Serialize)]
+pub struct GeneratedLine {
+    pub text: String,
+    /// Tokens generated by the model, not including added brackets and quotes
+    pub tokens: Vec<String>,
+    pub start_token: Option<String>,
+    /// Number of generated lines that were rejected by the content filter or
+    /// for being longer than the maximum length
+    pub retries: usize,
+    /// Generating with this seed (and the same model and options) gives the same line
+    pub seed: u64,
+    pub score: Score,
+}
+
+/// Builds a `Generator`. Only the model is required.
+pub struct GeneratorBuilder {
+    models: Vec<Arc<Model>>,
+    settings: Settings,
+    seed: Option<u64>,
+    rng: Option<Box<dyn RngCore + Send>>,
+}
+
+impl GeneratorBuilder {
+    /// Models can be shared between generators by passing an `Arc<Model>`.
+    /// Replaces any models given before.
+    pub fn model(mut self, model: impl Into<Arc<Model>>) -> Self {
+        self.models = vec![model.into()];
+        self
+    }
+
+    /// Generate each line from one of these models, chosen at random. Replaces
+    /// any models given before.
+    pub fn models<M: Into<Arc<Model>>>(mut self, models: impl IntoIterator<Item = M>) -> Self {
+        self.models = models.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Start each line with this token. Only works for models that include order 1.
+    pub fn start_token(mut self, token: Option<String>) -> Self {
+        self.settings.start_token = token;
+        self
+    }
+
+    /// Keep adding chains to each line until it has at least this many characters
+    pub fn min_length(mut self, length: Option<usize>) -> Self {
+        self.settings.min_length = length;
+        self
+    }
+
+    /// Regenerate lines that have more than this many characters
+    pub fn max_length(mut self, length: Option<usize>) -> Self {
+        self.settings.max_length = length;
+        self
+    }
+
+    /// See `--temperature` (defaults to 1). Can't be negative.
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.settings.temperature = temperature;
+        self
+    }
+
+    /// Generate this many candidates for each line and keep the best scoring one
+    /// (defaults to 1)
+    pub fn candidates(mut self, candidates: usize) -> Self {
+        self.settings.candidates = candidates;
+        self
+    }
+
+    pub fn filter(mut self, filter: ContentFilter) -> Self {
+        self.settings.filter = filter;
+        self
+    }
+
+    /// Used to group identical subtitle lines, and to check lines against the
+    /// content filter. Defaults to the tokenizer used for training.
+    pub fn tokenizer(mut self, tokenizer: Tokenizer) -> Self {
+        self.settings.tokenizer = tokenizer;
+        self
+    }
+
+    /// Seed of the first generated line. Each line after it uses the next seed.
+    /// If unspecified, a random seed is used.
+    pub fn seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Take the seed of each line from `rng` instead of counting up from
+    /// `seed`. Each line can still be reproduced from its `GeneratedLine::seed`.
+    pub fn rng(mut self, rng: impl RngCore + Send + 'static) -> Self {
+        self.rng = Some(Box::new(rng));
+        self
+    }
+
+    pub fn build(self) -> Result<Generator> {
+        if self.models.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Config,
+                "no model was given to the generator",
+            ));
+        }
+
+        check_temperature(self.settings.temperature)?;
+
+        let seeds = match self.rng {
+            Some(rng) => Seeds::Rng(rng),
+            None => Seeds::Sequential(self.seed.unwrap_or_else(rand::random)),
+        };
+
+        Ok(Generator {
+            models: self.models,
+            settings: Settings {
+                candidates: self.settings.candidates.max(1),
+                ..self.settings
+            },
+            seeds,
+        })
+    }
+}
+
+/// Generates lines of text from a model. Options are set with
+/// `Generator::builder`, and stay the same for every generated line.
+pub struct Generator {
+    models: Vec<Arc<Model>>,
+    settings: Settings,
+    seeds: Seeds,
+}
+
+/// Where the seed of each line comes from
+enum Seeds {
+    /// Counts up from the first seed
+    Sequential(u64),
+    Rng(Box<dyn RngCore + Send>),
+}
+
+/// Options that stay the same for every generated line
+struct Settings {
+    start_token: Option<String>,
     min_length: Option<usize>,
-) -> Result<()> {
+    max_length: Option<usize>,
+    temperature: f64,
+    candidates: usize,
+    filter: ContentFilter,
+    tokenizer: Tokenizer,
+}
+
+/// Generated line, along with the information needed to score it
+struct Candidate {
+    text: String,
+    /// Tokens of each chain that makes up the line
+    chains: Vec<Vec<String>>,
+    /// Number of brackets and quotes added to balance the line
+    added_symbols: usize,
+}
+
+impl Generator {
+    pub fn builder() -> GeneratorBuilder {
+        GeneratorBuilder {
+            models: Vec::new(),
+            settings: Settings {
+                start_token: None,
+                min_length: None,
+                max_length: None,
+                temperature: 1.0,
+                candidates: 1,
+                filter: ContentFilter::default(),
+                tokenizer: crate::train::tokenize,
+            },
+            seed: None,
+            rng: None,
+        }
+    }
+
+    /// The first model, if several were given
+    pub fn model(&self) -> &Model {
+        &self.models[0]
+    }
+
+    pub fn models(&self) -> &[Arc<Model>] {
+        &self.models
+    }
+
+    fn next_seed(&mut self) -> u64 {
+        match &mut self.seeds {
+            Seeds::Sequential(next) => {
+                let seed = *next;
+                *next = seed.wrapping_add(1);
+                seed
+            }
+            Seeds::Rng(rng) => rng.next_u64(),
+        }
+    }
+
+    pub fn generate_line(&mut self) -> Result<GeneratedLine> {
+        let seed = self.next_seed();
+        // `min_length` is only a minimum, so it shouldn't favor shorter lines
+        self.generate_from_seed(seed, None)
+    }
+
+    /// Generates lines endlessly
+    pub fn lines<'a>(&'a mut self) -> impl Iterator<Item = Result<GeneratedLine>> + 'a {
+        std::iter::repeat_with(move || self.generate_line())
+    }
+
+    /// Replaces the text of each line with generated text, and returns the score
+    /// of each generated line
+    pub fn generate_subtitle_file(
+        &mut self,
+        subtitle_file: &mut GenericSubtitleFile,
+    ) -> Result<HashMap<String, Score>> {
+        let tokenizer = self.settings.tokenizer;
+        replace_subtitle_lines(subtitle_file, tokenizer, |target_length| {
+            let seed = self.next_seed();
+            self.generate_from_seed(seed, Some(target_length))
+                .map(|line| (line.text, line.score))
+        })
+    }
+
+    fn generate_from_seed(&self, seed: u64, target_length: Option<usize>) -> Result<GeneratedLine> {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        // Only pick a model if there's a choice, so a seed gives the same line
+        // as before with a single model
+        let model = match self.models.len() {
+            1 => &self.models[0],
+            len => &self.models[rng.gen_range(0, len)],
+        };
+
+        self.settings
+            .generate_line(model, &mut rng, seed, target_length)
+    }
+}
+
+/// Replaces the text of each line with text returned by `generate`, which is
+/// given the length of the original line. Returns the score of each generated line.
+fn replace_subtitle_lines(
+    subtitle_file: &mut GenericSubtitleFile,
+    tokenizer: Tokenizer,
+    mut generate: impl FnMut(usize) -> Result<(String, Score)>,
+) -> Result<HashMap<String, Score>> {
     let mut subtitle_entries = subtitle_file
         .get_subtitle_entries()
-        .context(|| "failed to parse subtitle entries")?;
+        .context_kind(ErrorKind::Parse, || "failed to parse subtitle entries")?;
 
     // Lines that have the same tokenized output should get the same generated string
     let mut generated: HashMap<Vec<String>, String> = HashMap::new();
+    let mut scores: HashMap<String, Score> = HashMap::new();
 
     for mut subtitle in subtitle_entries.iter_mut() {
         if let Some(line) = subtitle.line.take() {
@@ -80,13 +390,20 @@ pub fn generate_subtitle_file(
             if line.trim().is_empty() || TYPESETTING.is_match(&line) {
                 subtitle.line = Some("".to_owned());
             } else {
-                match generated.entry(tokenize(&line)) {
+                match generated.entry(tokenizer(&line)) {
                     Entry::Occupied(e) => {
                         subtitle.line = Some(e.get().to_owned());
                     }
                     Entry::Vacant(e) => {
-                        let new_line = e.insert(generate_line(log, &chain, start, min_length)?);
+                        let mut original = String::new();
+                        write_tokens(e.key(), &mut original)
+                            .context(|| "failed to write tokens to output")?;
+
+                        let (text, score) = generate(original.chars().count())?;
+
+                        let new_line = e.insert(text);
                         subtitle.line = Some(new_line.to_owned());
+                        scores.insert(new_line.to_owned(), score);
                     }
                 }
             }
@@ -95,76 +412,200 @@ pub fn generate_subtitle_file(
 
     subtitle_file
         .update_subtitle_entries(&subtitle_entries)
-        .context(|| "failed to update subtitle lines")
-}
+        .context(|| "failed to update subtitle lines")?;
 
-pub fn generate_lines<'a>(
-    log: &'a Logger,
-    chain: Chain<String>,
-    start: Option<&'a str>,
-    min_length: Option<usize>,
-) -> impl Iterator<Item = Result<String>> + 'a {
-    std::iter::repeat_with(move || generate_line(log, &chain, start, min_length))
+    Ok(scores)
 }
 
-pub fn generate_line(
-    log: &Logger,
-    chain: &Chain<String>,
-    start_token: Option<&str>,
-    min_length: Option<usize>,
-) -> Result<String> {
-    let mut line = generate_single(log, &chain, start_token)?;
+impl Settings {
+    fn generate_line(
+        &self,
+        model: &Model,
+        rng: &mut impl Rng,
+        seed: u64,
+        target_length: Option<usize>,
+    ) -> Result<GeneratedLine> {
+        let (candidate, score, retries) = self.generate_best(model, rng, target_length)?;
+        Ok(GeneratedLine {
+            text: candidate.text,
+            tokens: candidate.chains.concat(),
+            start_token: self.start_token.clone(),
+            retries,
+            seed,
+            score,
+        })
+    }
 
-    if let Some(length) = min_length {
-        while line.chars().count() < length {
-            line.push(' ');
-            line.push_str(&generate_single(log, &chain, None)?);
+    /// Generates `candidates` lines and returns the one with the highest score,
+    /// along with the total number of retries. If `target_length` is specified,
+    /// lines closer to that many characters score higher.
+    fn generate_best(
+        &self,
+        model: &Model,
+        rng: &mut impl Rng,
+        target_length: Option<usize>,
+    ) -> Result<(Candidate, Score, usize)> {
+        let mut best: Option<(Candidate, Score)> = None;
+        let mut total_retries = 0;
+
+        for _ in 0..self.candidates {
+            let (candidate, retries) = self.generate_allowed_candidate(model, rng)?;
+            total_retries += retries;
+
+            let chains = candidate
+                .chains
+                .iter()
+                .enumerate()
+                .map(|(i, tokens)| GeneratedChain {
+                    tokens,
+                    from_start_token: i == 0 && self.start_token.is_some(),
+                });
+
+            let score = crate::score::score(
+                model,
+                chains,
+                candidate.text.chars().count(),
+                target_length,
+                candidate.added_symbols,
+            );
+
+            if best.as_ref().map_or(true, |(_, b)| score.total > b.total) {
+                best = Some((candidate, score));
+            }
         }
+
+        let (candidate, score) = best.expect("at least one candidate is generated");
+        Ok((candidate, score, total_retries))
     }
 
-    Ok(line)
-}
+    /// Generates candidates until one passes the content filter and is short
+    /// enough, and returns it along with the number of candidates that were rejected
+    fn generate_allowed_candidate(
+        &self,
+        model: &Model,
+        rng: &mut impl Rng,
+    ) -> Result<(Candidate, usize)> {
+        let mut blocked = 0;
+        let mut too_long = 0;
+
+        loop {
+            let candidate = self.generate_candidate(model, rng)?;
+
+            if self
+                .max_length
+                .map_or(false, |max| candidate.text.chars().count() > max)
+            {
+                too_long += 1;
+                if too_long >= MAX_LENGTH_ATTEMPTS {
+                    return Err(Error::new(
+                        ErrorKind::Constraint,
+                        format!(
+                            "failed to generate a short enough line after {} attempts",
+                            too_long
+                        ),
+                    ));
+                }
+                continue;
+            }
 
-fn generate_single(
-    log: &Logger,
-    chain: &Chain<String>,
-    start_token: Option<&str>,
-) -> Result<String> {
-    let generated = match start_token {
-        Some(start_token) => chain.generate_from_token(start_token.to_owned()),
-        None => chain.generate(),
-    };
+            // Avoid tokenizing the text if there's nothing to check it against
+            let is_blocked = !self.filter.is_empty()
+                && self
+                    .filter
+                    .is_blocked(&candidate.text, &(self.tokenizer)(&candidate.text));
+            if !is_blocked {
+                return Ok((candidate, blocked + too_long));
+            }
 
-    if generated.is_empty() && start_token.is_some() {
-        slog::error!(
-            log,
-            "Token was not found in the model \
-            (note that the `--start` param only works for models with order = 1)";
-            "token" => &start_token.unwrap()
-        );
-        return Err(Error::context("failed to generate chain from start token"));
+            if blocked >= self.filter.max_retries {
+                return Err(Error::new(
+                    ErrorKind::Constraint,
+                    format!("every generated line was blocked after {} retries", blocked),
+                ));
+            }
+            blocked += 1;
+        }
     }
 
-    let (pre, post) = balance_symbols(&generated);
+    fn generate_candidate(&self, model: &Model, rng: &mut impl Rng) -> Result<Candidate> {
+        let mut candidate = Candidate {
+            text: String::new(),
+            chains: Vec::new(),
+            added_symbols: 0,
+        };
 
-    let mut output = {
-        let size = generated.iter().fold(0, |acc, v| acc + v.len() + 1);
-        String::with_capacity(size)
-    };
+        // Only the first chain uses the start token
+        let mut start_token = self.start_token.as_ref().map(|s| s.as_ref());
+
+        loop {
+            let (text, tokens, added_symbols) = self.generate_single(model, start_token, rng)?;
 
-    let tokens_iter = pre
-        .into_iter()
-        .chain(generated.into_iter())
-        .chain(post.into_iter().rev().map(String::from));
+            if !candidate.text.is_empty() {
+                candidate.text.push(' ');
+            }
+            candidate.text.push_str(&text);
+            candidate.chains.push(tokens);
+            candidate.added_symbols += added_symbols;
 
-    write_tokens(tokens_iter, &mut output).context(|| "failed to write tokens to output")?;
+            start_token = None;
 
-    // TODO: This seems kinda hacky
-    if output.ends_with(" \"\"") {
-        output.truncate(output.len() - 3);
+            let long_enough = self
+                .min_length
+                .map_or(true, |length| candidate.text.chars().count() >= length);
+            if long_enough {
+                return Ok(candidate);
+            }
+        }
     }
 
-    Ok(output)
+    /// Returns the text, the generated tokens, and the number of symbols added to
+    /// balance brackets and quotes
+    fn generate_single(
+        &self,
+        model: &Model,
+        start_token: Option<&str>,
+        rng: &mut impl Rng,
+    ) -> Result<(String, Vec<String>, usize)> {
+        let generated = match start_token {
+            Some(start_token) => model.generate_from_token(start_token, self.temperature, rng),
+            None => model.generate(self.temperature, rng),
+        };
+
+        if generated.is_empty() {
+            return Err(match start_token {
+                Some(start_token) => Error::new(
+                    ErrorKind::Constraint,
+                    format!(
+                        "start token `{}` was not found in the model (note that start tokens \
+                         only work for models trained with order = 1, or with multiple orders \
+                         including 1)",
+                        start_token
+                    ),
+                ),
+                // Otherwise `min_length` would keep adding empty chains forever
+                None => Error::new(ErrorKind::Model, "model has no starting state"),
+            });
+        }
+
+        let (pre, post) = balance_symbols(&generated);
+        let added_symbols = pre.len() + post.len();
+
+        let mut output = {
+            let size = generated.iter().fold(0, |acc, v| acc + v.len() + 1);
+            String::with_capacity(size)
+        };
+
+        let tokens_iter = pre.iter().chain(generated.iter()).chain(post.iter().rev());
+
+        write_tokens(tokens_iter, &mut output).context(|| "failed to write tokens to output")?;
+
+        // TODO: This seems kinda hacky
+        if output.ends_with(" \"\"") {
+            output.truncate(output.len() - 3);
+        }
+
+        Ok((output, generated, added_symbols))
+    }
 }
 
 fn balance_symbols<T: AsRef<str>>(
@@ -264,3 +705,166 @@ fn write_tokens<T: AsRef<str>>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::RawChain;
+
+    fn model(transitions: &[(Option<&str>, Option<&str>, usize)]) -> Model {
+        let mut map = HashMap::new();
+        for &(from, to, count) in transitions {
+            map.entry(vec![from.map(String::from)])
+                .or_insert_with(HashMap::new)
+                .insert(to.map(String::from), count);
+        }
+        Model::Chain(RawChain { map, order: 1 })
+    }
+
+    #[test]
+    fn negative_temperatures_are_rejected() {
+        let result = Generator::builder()
+            .model(model(&[(None, Some("la"), 1), (Some("la"), None, 1)]))
+            .temperature(-0.5)
+            .build();
+        assert_eq!(result.err().map(|e| e.kind), Some(ErrorKind::Config));
+    }
+
+    #[test]
+    fn looping_models_end_at_zero_temperature() {
+        let mut generator = Generator::builder()
+            .model(model(&[
+                (None, Some("la"), 1),
+                (Some("la"), Some("la"), 3),
+                (Some("la"), None, 1),
+            ]))
+            .temperature(0.0)
+            .min_length(Some(500))
+            .seed(Some(1))
+            .build()
+            .unwrap();
+
+        let line = generator.generate_line().unwrap();
+        assert!(line.text.len() >= 500);
+        assert!(line.tokens.iter().all(|token| token == "la"));
+    }
+
+    #[test]
+    fn models_without_start_states_fail() {
+        let mut generator = Generator::builder()
+            .model(model(&[(Some("la"), None, 1)]))
+            .min_length(Some(10))
+            .build()
+            .unwrap();
+
+        let error = generator.generate_line().unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Model);
+    }
+
+    #[test]
+    fn min_length_is_not_a_target_length() {
+        let mut generator = Generator::builder()
+            .model(model(&[(None, Some("la"), 1), (Some("la"), None, 1)]))
+            .min_length(Some(5))
+            .candidates(3)
+            .build()
+            .unwrap();
+
+        let line = generator.generate_line().unwrap();
+        assert!(line.text.len() >= 5);
+        assert_eq!(line.score.length_fit, None);
+    }
+
+    #[test]
+    fn lines_are_generated_from_every_model() {
+        let mut generator = Generator::builder()
+            .models(vec![
+                model(&[(None, Some("a"), 1), (Some("a"), None, 1)]),
+                model(&[(None, Some("b"), 1), (Some("b"), None, 1)]),
+            ])
+            .seed(Some(1))
+            .build()
+            .unwrap();
+        assert_eq!(generator.models().len(), 2);
+
+        let lines = generator
+            .lines()
+            .take(20)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert!(lines.iter().any(|line| line.text.starts_with('a')));
+        assert!(lines.iter().any(|line| line.text.starts_with('b')));
+    }
+
+    #[test]
+    fn seeds_can_come_from_an_rng() {
+        let transitions = [
+            (None, Some("a"), 1),
+            (None, Some("b"), 1),
+            (Some("a"), None, 1),
+            (Some("b"), None, 1),
+        ];
+        let build = |rng: StdRng| {
+            Generator::builder()
+                .model(model(&transitions))
+                .rng(rng)
+                .build()
+                .unwrap()
+        };
+
+        let mut first = build(StdRng::seed_from_u64(1));
+        let mut second = build(StdRng::seed_from_u64(1));
+        let lines = first.lines().take(5).collect::<Result<Vec<_>>>().unwrap();
+        for line in &lines {
+            assert_eq!(second.generate_line().unwrap().text, line.text);
+        }
+
+        // Each line can still be reproduced from its seed
+        let mut generator = Generator::builder()
+            .model(model(&transitions))
+            .seed(Some(lines[3].seed))
+            .build()
+            .unwrap();
+        assert_eq!(generator.generate_line().unwrap().text, lines[3].text);
+    }
+
+    #[test]
+    fn output_formats() {
+        use structopt::StructOpt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.yaml");
+        let path = path.to_str().unwrap();
+        let model = model(&[(None, Some("la"), 1), (Some("la"), None, 1)]);
+        crate::save_model(&model, path, crate::ModelFormat::Yaml).unwrap();
+
+        let generate = |format: &str| {
+            let args = opts::Generate::from_iter(&[
+                "generate",
+                "-n",
+                "3",
+                "--output-format",
+                format,
+                path,
+            ]);
+            let mut output = Vec::new();
+            generate_from_opts(&crate::NoopObserver, args, &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(generate("text"), "la\nla\nla\n");
+
+        let jsonl = generate("jsonl");
+        let records = jsonl.lines().collect::<Vec<_>>();
+        assert_eq!(records.len(), 3);
+        for record in records {
+            let value: serde_json::Value = serde_json::from_str(record).unwrap();
+            assert_eq!(value["text"], "la");
+        }
+
+        let json: serde_json::Value = serde_json::from_str(&generate("json")).unwrap();
+        let array = json.as_array().unwrap();
+        assert_eq!(array.len(), 3);
+        assert!(array.iter().all(|value| value["text"] == "la"));
+    }
+}
@@ -1,12 +1,28 @@
 pub mod error;
 pub mod opts;
 
+mod filter;
 mod generate;
+mod model;
+mod observer;
+mod repl;
+mod score;
 mod screenshots;
+mod serve;
 mod train;
 
-pub use generate::{
-    generate_from_opts, generate_line, generate_lines, generate_subtitle_file, load_model,
+pub use filter::{load_filter, ContentFilter};
+pub use generate::{generate_from_opts, GeneratedLine, Generator, GeneratorBuilder, Tokenizer};
+pub use model::{
+    convert, load_model, prune, prune_model, save_model, BackoffModel, MappedModel, Model,
+    ModelFormat, PruneStats, RawChain, TransitionCounts,
+};
+pub use observer::{Event, Field, Level, NoopObserver, Observer};
+pub use repl::repl;
+pub use score::Score;
+pub use screenshots::{generate_screenshots, render_episode};
+pub use serve::serve;
+pub use train::{
+    get_subtitles_from_file, parse_subtitles, sanitize_file, tokenize, train, Deduplicator,
+    DropReason, SanitizedFile, SanitizedLine,
 };
-pub use screenshots::generate_screenshots;
-pub use train::{get_subtitles_from_file, parse_subtitles, train};
@@ -1,6 +1,37 @@
 use slog::Drain;
 use structopt::StructOpt;
 use subkatsu::opts::Opts;
+use subkatsu::{Event, Field, Level, Observer};
+
+/// Logs events from the library
+struct SlogObserver(slog::Logger);
+
+struct Fields<'a>(&'a [Field<'a>]);
+
+impl<'a> slog::KV for Fields<'a> {
+    fn serialize(
+        &self,
+        _record: &slog::Record,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        for (key, value) in self.0 {
+            serializer.emit_arguments(key, &format_args!("{}", value))?;
+        }
+        Ok(())
+    }
+}
+
+impl Observer for SlogObserver {
+    fn event(&self, event: &Event) {
+        let fields = Fields(event.fields);
+        match event.level {
+            Level::Debug => slog::debug!(self.0, "{}", event.message; fields),
+            Level::Info => slog::info!(self.0, "{}", event.message; fields),
+            Level::Warning => slog::warn!(self.0, "{}", event.message; fields),
+            Level::Error => slog::error!(self.0, "{}", event.message; fields),
+        }
+    }
+}
 
 fn main() {
     let decorator = slog_term::TermDecorator::new().build();
@@ -8,22 +39,34 @@ fn main() {
     let drain = slog_async::Async::new(drain).build().fuse();
 
     let log = slog::Logger::root(drain, slog::o!());
+    let observer = SlogObserver(log.clone());
 
     let result = match Opts::from_args() {
-        Opts::Train(args) => subkatsu::train(&log, args),
-        Opts::Generate(args) => subkatsu::generate_from_opts(&log, args, &mut std::io::stdout()),
-        Opts::Screenshots(args) => subkatsu::generate_screenshots(&log, args),
+        Opts::Train(args) => subkatsu::train(&observer, args, &mut std::io::stdout()),
+        Opts::Generate(args) => {
+            subkatsu::generate_from_opts(&observer, args, &mut std::io::stdout())
+        }
+        Opts::Screenshots(args) => subkatsu::generate_screenshots(&observer, args),
+        Opts::Render(args) => subkatsu::render_episode(&observer, args),
+        Opts::Repl(args) => {
+            let stdin = std::io::stdin();
+            subkatsu::repl(&observer, args, stdin.lock(), &mut std::io::stdout())
+        }
+        Opts::Serve(args) => subkatsu::serve(&observer, args),
+        Opts::Convert(args) => subkatsu::convert(&observer, args),
+        Opts::Prune(args) => subkatsu::prune(&observer, args),
     };
 
     if let Err(err) = result {
-        slog::error!(log, "Encountered error"; "description" => %err);
+        slog::error!(log, "Encountered error"; "description" => %err, "kind" => %err.kind());
 
         for cause in err.iter().skip(1) {
             slog::error!(log, "Underlying error"; "description" => %cause);
         }
 
         // Drop the logger so the messages get flushed before exiting
+        std::mem::drop(observer);
         std::mem::drop(log);
-        std::process::exit(1);
+        std::process::exit(err.kind().exit_code());
     }
 }
@@ -0,0 +1,139 @@
+use super::RawChain;
+use std::collections::HashMap;
+
+/// Default for `BackoffModel::min_support`
+pub const DEFAULT_MIN_SUPPORT: usize = 2;
+
+/// Model made of chains of several different orders, trained on the same data.
+/// At each step, the highest order chain is used, unless the current state was
+/// seen too rarely, in which case it backs off to the next lower order.
+#[derive(Debug)]
+pub struct BackoffModel {
+    /// Sorted by order, from highest to lowest
+    chains: Vec<RawChain>,
+    /// Minimum number of times a state needs to have been seen in the training
+    /// data for its chain to be used (the lowest order chain is always used)
+    pub min_support: usize,
+}
+
+impl BackoffModel {
+    pub fn new(mut chains: Vec<RawChain>) -> Self {
+        chains.sort_by(|a, b| b.order.cmp(&a.order));
+        BackoffModel {
+            chains,
+            min_support: DEFAULT_MIN_SUPPORT,
+        }
+    }
+
+    pub fn chains(&self) -> &[RawChain] {
+        &self.chains
+    }
+
+    pub fn chains_mut(&mut self) -> &mut [RawChain] {
+        &mut self.chains
+    }
+
+    pub fn into_chains(self) -> Vec<RawChain> {
+        self.chains
+    }
+
+    pub fn max_order(&self) -> usize {
+        self.chains.first().map_or(0, |chain| chain.order)
+    }
+
+    /// Transitions from the highest order chain with enough support for the
+    /// current state, given at least `max_order` previous tokens
+    pub fn transitions(
+        &self,
+        history: &[Option<String>],
+    ) -> Option<&HashMap<Option<String>, usize>> {
+        let last = self.chains.len().saturating_sub(1);
+
+        self.chains.iter().enumerate().find_map(|(i, chain)| {
+            let state = &history[history.len() - chain.order..];
+            chain
+                .map
+                .get(state)
+                .filter(|t| i == last || t.values().sum::<usize>() >= self.min_support)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::test_helpers::chain;
+
+    fn model() -> BackoffModel {
+        let lines = &["x a b", "y a c", "y a c"];
+        BackoffModel::new(vec![chain(1, lines), chain(2, lines)])
+    }
+
+    /// Successors of `history` in the chain that gets used, sorted by token
+    fn successors(model: &BackoffModel, history: &[Option<&str>]) -> Option<Vec<(String, usize)>> {
+        let history = history
+            .iter()
+            .map(|token| token.map(String::from))
+            .collect::<Vec<_>>();
+        let mut successors = model
+            .transitions(&history)?
+            .iter()
+            .map(|(token, count)| (token.clone().unwrap_or_default(), *count))
+            .collect::<Vec<_>>();
+        successors.sort();
+        Some(successors)
+    }
+
+    fn counts(pairs: &[(&str, usize)]) -> Option<Vec<(String, usize)>> {
+        Some(pairs.iter().map(|&(t, c)| (t.to_owned(), c)).collect())
+    }
+
+    #[test]
+    fn chains_are_sorted_by_order() {
+        let model = model();
+        assert_eq!(model.max_order(), 2);
+        assert_eq!(
+            model.chains().iter().map(|c| c.order).collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+    }
+
+    #[test]
+    fn uses_highest_order_with_enough_support() {
+        let model = model();
+        assert_eq!(
+            successors(&model, &[Some("y"), Some("a")]),
+            counts(&[("c", 2)])
+        );
+    }
+
+    #[test]
+    fn backs_off_for_rare_states() {
+        let mut model = model();
+        assert_eq!(
+            successors(&model, &[Some("x"), Some("a")]),
+            counts(&[("b", 1), ("c", 2)])
+        );
+
+        model.min_support = 1;
+        assert_eq!(
+            successors(&model, &[Some("x"), Some("a")]),
+            counts(&[("b", 1)])
+        );
+    }
+
+    #[test]
+    fn backs_off_for_unknown_states() {
+        let model = model();
+        assert_eq!(
+            successors(&model, &[Some("z"), Some("a")]),
+            counts(&[("b", 1), ("c", 2)])
+        );
+        // The lowest order is used even without enough support
+        assert_eq!(
+            successors(&model, &[Some("z"), Some("b")]),
+            counts(&[("", 1)])
+        );
+        assert_eq!(successors(&model, &[Some("a"), Some("z")]), None);
+    }
+}
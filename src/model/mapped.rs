@@ -0,0 +1,565 @@
+use super::{sample_index, RawChain, TransitionCounts};
+use crate::error::*;
+use memmap::Mmap;
+use rand::Rng;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+// File layout (all integers are little-endian):
+//
+// Header (64 bytes):
+//    0  magic bytes (see `super::MAGIC`), version, encoding, padding
+//   16  order: u32
+//   20  number of tokens: u32
+//   24  number of states: u64
+//   32  offset of the token table: u64
+//   40  offset of the string data: u64
+//   48  offset of the state table: u64
+//   56  offset of the transition table: u64
+//
+// Token table: (number of tokens + 1) u64 offsets into the string data, where
+// token N spans offsets[N - 1]..offsets[N]. Tokens are sorted, so they can be
+// looked up with a binary search. Token ID 0 is the start/end marker.
+//
+// State table: one entry per state, sorted by token IDs, each containing
+// `order` u32 token IDs, followed by a u64 index into the transition table,
+// and a u32 number of transitions.
+//
+// Transition table: pairs of u32 token ID and u32 count.
+pub const HEADER_LEN: usize = 64;
+const TRANSITION_LEN: usize = 8;
+
+/// Files with a higher order are assumed to be corrupted
+const MAX_ORDER: usize = 255;
+
+/// Start/end marker, equivalent to `None` in a `RawChain`
+const END: u32 = 0;
+
+/// Model that reads states and transitions directly from a memory-mapped file,
+/// rather than loading the whole model into memory
+#[derive(Debug)]
+pub struct MappedModel {
+    mmap: Mmap,
+    order: usize,
+    token_count: usize,
+    state_count: usize,
+    tokens_offset: usize,
+    strings_offset: usize,
+    states_offset: usize,
+    transitions_offset: usize,
+    /// Number of transitions that fit between the transition table's offset
+    /// and the end of the file
+    transition_count: usize,
+}
+
+// Out-of-bounds reads (from a corrupted file) act as the end marker
+fn read_u32(bytes: &[u8], pos: usize) -> u32 {
+    match pos.checked_add(4).and_then(|end| bytes.get(pos..end)) {
+        Some(b) => u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        None => 0,
+    }
+}
+
+fn read_u64(bytes: &[u8], pos: usize) -> u64 {
+    match pos.checked_add(8).and_then(|end| bytes.get(pos..end)) {
+        Some(b) => u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]),
+        None => 0,
+    }
+}
+
+/// Reads a size or offset, which may not fit in a `usize` on 32-bit platforms
+fn read_usize(bytes: &[u8], pos: usize) -> Option<usize> {
+    let value = read_u64(bytes, pos);
+    if value > usize::max_value() as u64 {
+        None
+    } else {
+        Some(value as usize)
+    }
+}
+
+impl MappedModel {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = File::open(path).context(|| "failed to open model file")?;
+
+        // Safety: the model file is expected not to be modified while it's in use
+        let mmap = unsafe { Mmap::map(&file) }.context(|| "failed to memory-map model file")?;
+
+        if mmap.len() < HEADER_LEN {
+            return Err(Error::new(ErrorKind::Model, "model file is truncated"));
+        }
+
+        Self::from_mmap(mmap).ok_or_else(|| Error::new(ErrorKind::Model, "model file is corrupted"))
+    }
+
+    /// Reads the header, returning `None` if the tables it describes overlap
+    /// or don't fit in the file
+    fn from_mmap(mmap: Mmap) -> Option<Self> {
+        let order = read_u32(&mmap, 16) as usize;
+        if order == 0 || order > MAX_ORDER {
+            return None;
+        }
+
+        let token_count = read_u32(&mmap, 20) as usize;
+        let state_count = read_usize(&mmap, 24)?;
+        let tokens_offset = read_usize(&mmap, 32)?;
+        let strings_offset = read_usize(&mmap, 40)?;
+        let states_offset = read_usize(&mmap, 48)?;
+        let transitions_offset = read_usize(&mmap, 56)?;
+
+        let tokens_end = token_count
+            .checked_add(1)?
+            .checked_mul(8)?
+            .checked_add(tokens_offset)?;
+        let states_end = state_count
+            .checked_mul(order * 4 + 12)?
+            .checked_add(states_offset)?;
+
+        if tokens_offset < HEADER_LEN
+            || tokens_end > strings_offset
+            || strings_offset > states_offset
+            || states_end > transitions_offset
+            || transitions_offset > mmap.len()
+        {
+            return None;
+        }
+
+        Some(MappedModel {
+            order,
+            token_count,
+            state_count,
+            tokens_offset,
+            strings_offset,
+            states_offset,
+            transitions_offset,
+            transition_count: (mmap.len() - transitions_offset) / TRANSITION_LEN,
+            mmap,
+        })
+    }
+
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    fn state_len(&self) -> usize {
+        self.order * 4 + 12
+    }
+
+    fn token(&self, id: u32) -> Option<&[u8]> {
+        if id == END || id as usize > self.token_count {
+            return None;
+        }
+
+        // The token table was checked to fit in the file, but the offsets in it
+        // may still point anywhere
+        let pos = self.tokens_offset + (id as usize - 1) * 8;
+        let start = self
+            .strings_offset
+            .checked_add(read_usize(&self.mmap, pos)?)?;
+        let end = self
+            .strings_offset
+            .checked_add(read_usize(&self.mmap, pos + 8)?)?;
+        self.mmap.get(start..end)
+    }
+
+    fn token_string(&self, id: u32) -> String {
+        String::from_utf8_lossy(self.token(id).unwrap_or(&[])).into_owned()
+    }
+
+    fn token_id(&self, token: &str) -> Option<u32> {
+        let (mut low, mut high) = (1, self.token_count + 1);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.token(mid as u32).unwrap_or(&[]).cmp(token.as_bytes()) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Some(mid as u32),
+            }
+        }
+        None
+    }
+
+    fn state_key(&self, index: usize) -> impl Iterator<Item = u32> + '_ {
+        let pos = self.states_offset + index * self.state_len();
+        (0..self.order).map(move |i| read_u32(&self.mmap, pos + i * 4))
+    }
+
+    /// Returns the index of the first transition and the number of transitions
+    fn find_state(&self, state: &[u32]) -> Option<(usize, usize)> {
+        let (mut low, mut high) = (0, self.state_count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.state_key(mid).cmp(state.iter().cloned()) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return self.transitions_at(mid),
+            }
+        }
+        None
+    }
+
+    /// Returns `None` if the transitions don't fit in the file
+    fn transitions_at(&self, index: usize) -> Option<(usize, usize)> {
+        let pos = self.states_offset + index * self.state_len() + self.order * 4;
+        let start = read_usize(&self.mmap, pos)?;
+        let len = read_u32(&self.mmap, pos + 8) as usize;
+
+        if start.checked_add(len)? > self.transition_count {
+            None
+        } else {
+            Some((start, len))
+        }
+    }
+
+    fn transition(&self, index: usize) -> (u32, u32) {
+        let pos = self.transitions_offset + index * TRANSITION_LEN;
+        (read_u32(&self.mmap, pos), read_u32(&self.mmap, pos + 4))
+    }
+
+    /// Returns the index of the first transition and the number of transitions,
+    /// given at least `order` previous tokens
+    pub fn transitions(&self, history: &[Option<String>]) -> Option<(usize, usize)> {
+        let state = history[history.len() - self.order..]
+            .iter()
+            .map(|token| match token {
+                None => Some(END),
+                Some(token) => self.token_id(token),
+            })
+            .collect::<Option<Vec<u32>>>()?;
+
+        self.find_state(&state)
+    }
+
+    pub fn next_token(
+        &self,
+        history: &[Option<String>],
+        temperature: f64,
+        rng: &mut impl Rng,
+    ) -> Option<String> {
+        let (start, len) = self.transitions(history)?;
+        let counts = (start..start + len).map(|i| self.transition(i).1 as usize);
+        let index = sample_index(counts, temperature, rng)?;
+
+        match self.transition(start + index).0 {
+            END => None,
+            token => Some(self.token_string(token)),
+        }
+    }
+
+    pub fn successors(&self, history: &[Option<String>]) -> Option<Vec<(Option<String>, usize)>> {
+        let (start, len) = self.transitions(history)?;
+        let successors = (start..start + len)
+            .map(|i| {
+                let (token, count) = self.transition(i);
+                let token = match token {
+                    END => None,
+                    token => Some(self.token_string(token)),
+                };
+                (token, count as usize)
+            })
+            .collect();
+
+        Some(successors)
+    }
+
+    pub fn transition_counts(
+        &self,
+        history: &[Option<String>],
+        next: Option<&str>,
+    ) -> Option<TransitionCounts> {
+        let (start, len) = self.transitions(history)?;
+        let next = match next {
+            None => Some(END),
+            Some(token) => self.token_id(token),
+        };
+
+        let mut counts = TransitionCounts {
+            count: 0,
+            total: 0,
+            successors: len,
+        };
+        for i in start..start + len {
+            let (token, count) = self.transition(i);
+            if Some(token) == next {
+                counts.count = count as usize;
+            }
+            counts.total += count as usize;
+        }
+
+        Some(counts)
+    }
+
+    /// Reads the whole model into memory
+    pub fn to_raw(&self) -> Result<RawChain> {
+        let to_token = |id| match id {
+            END => None,
+            id => Some(self.token_string(id)),
+        };
+
+        let mut map = HashMap::with_capacity(self.state_count);
+        for index in 0..self.state_count {
+            let key = self.state_key(index).map(to_token).collect();
+            let (start, len) = self
+                .transitions_at(index)
+                .ok_or_else(|| Error::new(ErrorKind::Model, "model file is corrupted"))?;
+            let transitions = (start..start + len)
+                .map(|i| {
+                    let (token, count) = self.transition(i);
+                    (to_token(token), count as usize)
+                })
+                .collect();
+            map.insert(key, transitions);
+        }
+
+        Ok(RawChain {
+            map,
+            order: self.order,
+        })
+    }
+}
+
+/// Writes everything after the first 16 bytes of the header. Fails if the
+/// chain doesn't fit in the format's 32-bit IDs and counts.
+pub fn write(chain: &RawChain, output: &mut impl Write) -> Result<()> {
+    if chain.order > MAX_ORDER {
+        return Err(too_large("order"));
+    }
+
+    let mut tokens = chain
+        .map
+        .iter()
+        .flat_map(|(state, transitions)| state.iter().chain(transitions.keys()))
+        .filter_map(|token| token.as_ref().map(|t| t.as_str()))
+        .collect::<Vec<&str>>();
+    tokens.sort_unstable();
+    tokens.dedup();
+
+    // IDs start at 1, after `END`, and a state can be followed by every token
+    // and the end, so this also bounds the number of transitions per state
+    if tokens.len() >= u32::max_value() as usize {
+        return Err(too_large("number of tokens"));
+    }
+
+    let ids = tokens
+        .iter()
+        .enumerate()
+        .map(|(i, token)| (*token, i as u32 + 1))
+        .collect::<HashMap<&str, u32>>();
+    let to_id = |token: &Option<String>| token.as_ref().map_or(END, |t| ids[t.as_str()]);
+
+    let mut states = Vec::with_capacity(chain.map.len());
+    for (state, transitions) in &chain.map {
+        let transitions = transitions
+            .iter()
+            .map(|(token, &count)| match to_u32(count) {
+                Some(count) => Ok((to_id(token), count)),
+                None => Err(too_large("transition count")),
+            })
+            .collect::<Result<Vec<(u32, u32)>>>()?;
+        states.push((state.iter().map(&to_id).collect::<Vec<u32>>(), transitions));
+    }
+    states.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    write_tables(chain.order, &tokens, &states, output)
+        .context_kind(ErrorKind::Io, || "failed to write mapped model")
+}
+
+fn to_u32(value: usize) -> Option<u32> {
+    if value > u32::max_value() as usize {
+        None
+    } else {
+        Some(value as u32)
+    }
+}
+
+fn too_large(what: &str) -> Error {
+    Error::new(
+        ErrorKind::Model,
+        format!("{} is too large for a mapped model", what),
+    )
+}
+
+fn write_tables(
+    order: usize,
+    tokens: &[&str],
+    states: &[(Vec<u32>, Vec<(u32, u32)>)],
+    output: &mut impl Write,
+) -> std::io::Result<()> {
+    let strings_len = tokens.iter().map(|t| t.len()).sum::<usize>();
+    let tokens_offset = HEADER_LEN;
+    let strings_offset = tokens_offset + (tokens.len() + 1) * 8;
+    let states_offset = strings_offset + strings_len;
+    let transitions_offset = states_offset + states.len() * (order * 4 + 12);
+
+    // The order and number of tokens were checked to fit
+    output.write_all(&(order as u32).to_le_bytes())?;
+    output.write_all(&(tokens.len() as u32).to_le_bytes())?;
+    output.write_all(&(states.len() as u64).to_le_bytes())?;
+    for offset in &[
+        tokens_offset,
+        strings_offset,
+        states_offset,
+        transitions_offset,
+    ] {
+        output.write_all(&(*offset as u64).to_le_bytes())?;
+    }
+
+    let mut offset = 0u64;
+    output.write_all(&offset.to_le_bytes())?;
+    for token in tokens {
+        offset += token.len() as u64;
+        output.write_all(&offset.to_le_bytes())?;
+    }
+
+    for token in tokens {
+        output.write_all(token.as_bytes())?;
+    }
+
+    let mut transition_index = 0u64;
+    for (state, transitions) in states {
+        for id in state {
+            output.write_all(&id.to_le_bytes())?;
+        }
+        output.write_all(&transition_index.to_le_bytes())?;
+        output.write_all(&(transitions.len() as u32).to_le_bytes())?;
+        transition_index += transitions.len() as u64;
+    }
+
+    for (_, transitions) in states {
+        for (id, count) in transitions {
+            output.write_all(&id.to_le_bytes())?;
+            output.write_all(&count.to_le_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{save_model, Model, ModelFormat};
+    use super::*;
+
+    fn chain() -> RawChain {
+        let token = |t: &str| Some(t.to_owned());
+        let transitions = |pairs: &[(Option<String>, usize)]| pairs.iter().cloned().collect();
+
+        let mut map = HashMap::new();
+        map.insert(vec![None, None], transitions(&[(token("hello"), 2)]));
+        map.insert(
+            vec![None, token("hello")],
+            transitions(&[(token("there"), 1), (token("you"), 1)]),
+        );
+        map.insert(
+            vec![token("hello"), token("there")],
+            transitions(&[(None, 1)]),
+        );
+        map.insert(
+            vec![token("hello"), token("you")],
+            transitions(&[(None, 1)]),
+        );
+
+        RawChain { map, order: 2 }
+    }
+
+    /// Saves `chain()` as a mapped model, and returns the file's contents
+    fn write_model(path: &str) -> Vec<u8> {
+        save_model(&Model::Chain(chain()), path, ModelFormat::Mapped).unwrap();
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn write_then_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.bin");
+        let path = path.to_str().unwrap();
+        write_model(path);
+
+        let model = MappedModel::open(path).unwrap();
+        assert_eq!(model.order(), 2);
+        assert_eq!(model.to_raw().unwrap().map, chain().map);
+
+        let history = vec![None, Some("hello".to_owned())];
+        let mut successors = model.successors(&history).unwrap();
+        successors.sort();
+        assert_eq!(
+            successors,
+            vec![(Some("there".to_owned()), 1), (Some("you".to_owned()), 1)]
+        );
+        assert_eq!(
+            model.transition_counts(&history, Some("you")),
+            Some(TransitionCounts {
+                count: 1,
+                total: 2,
+                successors: 2,
+            })
+        );
+        assert_eq!(model.transitions(&[None, Some("bye".to_owned())]), None);
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.bin");
+        let path = path.to_str().unwrap();
+        let bytes = write_model(path);
+
+        // Empty files fail to be mapped, with an IO error
+        for len in 1..bytes.len() {
+            std::fs::write(path, &bytes[..len]).unwrap();
+            let error = MappedModel::open(path)
+                .and_then(|model| model.to_raw())
+                .unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Model, "truncated to {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn overflowing_headers_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.bin");
+        let path = path.to_str().unwrap();
+        let bytes = write_model(path);
+
+        let fields: &[(usize, &[u8])] = &[
+            // Order
+            (16, &[0; 4]),
+            (16, &[0xff; 4]),
+            // Number of tokens, then each u64 field
+            (20, &[0xff; 4]),
+            (24, &[0xff; 8]),
+            (32, &[0xff; 8]),
+            (40, &[0xff; 8]),
+            (48, &[0xff; 8]),
+            (56, &[0xff; 8]),
+        ];
+
+        for &(pos, value) in fields {
+            let mut corrupted = bytes.clone();
+            corrupted[pos..pos + value.len()].copy_from_slice(value);
+            std::fs::write(path, &corrupted).unwrap();
+
+            let error = MappedModel::open(path).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Model, "corrupted at byte {}", pos);
+        }
+    }
+
+    #[test]
+    fn chains_too_large_are_rejected() {
+        let mut high_order = chain();
+        high_order.order = MAX_ORDER + 1;
+        let error = write(&high_order, &mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Model);
+
+        // Counts are stored as u32
+        if std::mem::size_of::<usize>() > 4 {
+            let mut common = chain();
+            for count in common.map.get_mut(&vec![None, None]).unwrap().values_mut() {
+                *count = u32::max_value() as usize + 1;
+            }
+            let error = write(&common, &mut Vec::new()).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Model);
+        }
+    }
+}
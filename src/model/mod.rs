@@ -0,0 +1,714 @@
+mod backoff;
+mod mapped;
+mod prune;
+
+pub use self::backoff::BackoffModel;
+pub use self::mapped::MappedModel;
+pub use self::prune::{prune, prune_model, PruneStats};
+
+use crate::error::*;
+use crate::observer::Observer;
+use crate::opts;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+/// Magic bytes at the start of binary model files. Files without it are
+/// assumed to be YAML.
+const MAGIC: &[u8; 8] = b"SUBKATSU";
+const VERSION: u8 = 1;
+
+// The byte after the version describes how the rest of the file is encoded
+const UNCOMPRESSED: u8 = 0;
+const GZIP: u8 = 1;
+const MAPPED: u8 = 2;
+const BACKOFF: u8 = 3;
+const BACKOFF_GZIP: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModelFormat {
+    Yaml,
+    Binary,
+    Compressed,
+    Mapped,
+}
+
+impl ModelFormat {
+    /// Models trained with multiple orders can only be saved in some formats
+    pub fn check_orders(self, orders: usize) -> Result<()> {
+        match self {
+            ModelFormat::Binary | ModelFormat::Compressed => Ok(()),
+            ModelFormat::Yaml | ModelFormat::Mapped if orders <= 1 => Ok(()),
+            _ => Err(multiple_orders_error()),
+        }
+    }
+}
+
+/// Checks that every order is at least 1, and that no order is repeated
+pub fn check_orders(orders: &[usize]) -> Result<()> {
+    for (i, &order) in orders.iter().enumerate() {
+        if order == 0 {
+            return Err(Error::new(ErrorKind::Config, "orders must be at least 1"));
+        }
+        if orders[..i].contains(&order) {
+            return Err(Error::new(
+                ErrorKind::Config,
+                format!("order {} is given more than once", order),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn multiple_orders_error() -> Error {
+    Error::new(
+        ErrorKind::Config,
+        "models with multiple orders can only be saved \
+         in the binary or compressed formats",
+    )
+}
+
+impl std::str::FromStr for ModelFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "yaml" => Ok(ModelFormat::Yaml),
+            "binary" => Ok(ModelFormat::Binary),
+            "compressed" => Ok(ModelFormat::Compressed),
+            "mapped" => Ok(ModelFormat::Mapped),
+            _ => Err(Error::new(ErrorKind::Config, "unknown model format")),
+        }
+    }
+}
+
+/// Markov chain mapping each state (the last `order` tokens) to the tokens that
+/// followed it, with how many times each was seen. `None` marks the start or end
+/// of a line. Serializes the same way as a `markov::Chain`, so YAML models saved
+/// by older versions can still be loaded.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RawChain {
+    pub map: HashMap<Vec<Option<String>>, HashMap<Option<String>, usize>>,
+    pub order: usize,
+}
+
+impl RawChain {
+    pub fn of_order(order: usize) -> Self {
+        RawChain {
+            map: HashMap::new(),
+            order,
+        }
+    }
+
+    /// Adds a line to the chain. Equivalent to `markov::Chain::feed`.
+    pub fn feed(&mut self, tokens: &[String]) {
+        if tokens.is_empty() {
+            return;
+        }
+
+        let mut line = vec![None; self.order];
+        line.extend(tokens.iter().cloned().map(Some));
+        line.push(None);
+
+        for window in line.windows(self.order + 1) {
+            let (state, next) = window.split_at(self.order);
+            *self
+                .map
+                .entry(state.to_vec())
+                .or_insert_with(HashMap::new)
+                .entry(next[0].clone())
+                .or_insert(0) += 1;
+        }
+    }
+}
+
+/// Chains end after this many tokens, even if the model would keep going. At low
+/// temperatures, a state that's most often followed by itself would otherwise
+/// repeat forever.
+pub const MAX_CHAIN_TOKENS: usize = 100;
+
+/// Picks an index with a probability proportional to `count ^ (1 / temperature)`.
+/// Lower temperatures favor common options, and a temperature of 0 always picks
+/// the most common one. Higher temperatures make all options more equally likely.
+fn sample_index(
+    counts: impl Iterator<Item = usize> + Clone,
+    temperature: f64,
+    rng: &mut impl Rng,
+) -> Option<usize> {
+    let weight = |count: usize| (count as f64).powf(1.0 / temperature);
+    let total = counts.clone().map(weight).sum::<f64>();
+
+    // Very low temperatures can overflow the weights
+    if temperature <= 0.0 || !total.is_finite() {
+        return counts
+            .enumerate()
+            .max_by_key(|&(_, count)| count)
+            .map(|(i, _)| i);
+    }
+
+    let mut remaining = rng.gen::<f64>() * total;
+    let mut last = None;
+    for (i, count) in counts.enumerate() {
+        let w = weight(count);
+        if remaining < w {
+            return Some(i);
+        }
+        if w > 0.0 {
+            last = Some(i);
+        }
+        remaining -= w;
+    }
+
+    // Only reachable due to floating point rounding
+    last
+}
+
+fn sample_token(
+    transitions: &HashMap<Option<String>, usize>,
+    temperature: f64,
+    rng: &mut impl Rng,
+) -> Option<String> {
+    // Sorted, since `HashMap` iteration order would otherwise make seeded
+    // generation differ between runs
+    let mut transitions = transitions.iter().collect::<Vec<_>>();
+    transitions.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+    let index = sample_index(
+        transitions.iter().map(|&(_, count)| *count),
+        temperature,
+        rng,
+    )?;
+    transitions[index].0.clone()
+}
+
+/// How often a token followed a state in the training data
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransitionCounts {
+    /// Number of times the token followed the state
+    pub count: usize,
+    /// Number of times the state was followed by any token
+    pub total: usize,
+    /// Number of different tokens that followed the state
+    pub successors: usize,
+}
+
+impl TransitionCounts {
+    fn from_map(transitions: &HashMap<Option<String>, usize>, next: Option<&str>) -> Self {
+        TransitionCounts {
+            count: transitions
+                .get(&next.map(String::from))
+                .cloned()
+                .unwrap_or(0),
+            total: transitions.values().sum(),
+            successors: transitions.len(),
+        }
+    }
+}
+
+/// A model loaded from a file
+#[derive(Debug)]
+pub enum Model {
+    Chain(RawChain),
+    Mapped(MappedModel),
+    Backoff(BackoffModel),
+}
+
+impl Model {
+    /// For models trained with multiple orders, this is the highest order
+    pub fn order(&self) -> usize {
+        match self {
+            Model::Chain(chain) => chain.order,
+            Model::Mapped(mapped) => mapped.order(),
+            Model::Backoff(backoff) => backoff.max_order(),
+        }
+    }
+
+    /// Whether there are any transitions for the last `order` tokens
+    fn has_state(&self, history: &[Option<String>]) -> bool {
+        match self {
+            Model::Chain(chain) => chain.map.contains_key(history),
+            Model::Mapped(mapped) => mapped.transitions(history).is_some(),
+            Model::Backoff(backoff) => backoff.transitions(history).is_some(),
+        }
+    }
+
+    /// Counts for `next` (or the end of the chain, if `None`) following the
+    /// last `order` tokens of `history`. Returns `None` if the state is unknown.
+    pub fn transition_counts(
+        &self,
+        history: &[Option<String>],
+        next: Option<&str>,
+    ) -> Option<TransitionCounts> {
+        match self {
+            Model::Chain(chain) => chain
+                .map
+                .get(history)
+                .map(|transitions| TransitionCounts::from_map(transitions, next)),
+            Model::Mapped(mapped) => mapped.transition_counts(history, next),
+            Model::Backoff(backoff) => backoff
+                .transitions(history)
+                .map(|transitions| TransitionCounts::from_map(transitions, next)),
+        }
+    }
+
+    /// Tokens that followed the last `order` tokens of `history` (`None` for the
+    /// end of the chain), with how many times each was seen, most common first
+    pub fn successors(&self, history: &[Option<String>]) -> Option<Vec<(Option<String>, usize)>> {
+        let to_vec = |transitions: &HashMap<Option<String>, usize>| {
+            transitions
+                .iter()
+                .map(|(token, count)| (token.clone(), *count))
+                .collect::<Vec<_>>()
+        };
+
+        let mut successors = match self {
+            Model::Chain(chain) => to_vec(chain.map.get(history)?),
+            Model::Mapped(mapped) => mapped.successors(history)?,
+            Model::Backoff(backoff) => to_vec(backoff.transitions(history)?),
+        };
+
+        successors.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Some(successors)
+    }
+
+    /// Returns `None` at the end of the chain
+    fn next_token(
+        &self,
+        history: &[Option<String>],
+        temperature: f64,
+        rng: &mut impl Rng,
+    ) -> Option<String> {
+        match self {
+            Model::Chain(chain) => sample_token(chain.map.get(history)?, temperature, rng),
+            Model::Mapped(mapped) => mapped.next_token(history, temperature, rng),
+            Model::Backoff(backoff) => {
+                sample_token(backoff.transitions(history)?, temperature, rng)
+            }
+        }
+    }
+
+    fn generate_from_history(
+        &self,
+        mut history: Vec<Option<String>>,
+        mut output: Vec<String>,
+        temperature: f64,
+        rng: &mut impl Rng,
+    ) -> Vec<String> {
+        while output.len() < MAX_CHAIN_TOKENS {
+            let token = match self.next_token(&history, temperature, rng) {
+                Some(token) => token,
+                None => break,
+            };
+            history.remove(0);
+            history.push(Some(token.clone()));
+            output.push(token);
+        }
+
+        output
+    }
+
+    /// Equivalent to `Chain::generate`, but stops after `MAX_CHAIN_TOKENS`.
+    /// See `sample_index` for how the temperature affects the output.
+    pub fn generate(&self, temperature: f64, rng: &mut impl Rng) -> Vec<String> {
+        self.generate_from_history(vec![None; self.order()], Vec::new(), temperature, rng)
+    }
+
+    /// Equivalent to `Chain::generate_from_token`. Models trained with multiple
+    /// orders can start from any token, as long as one of the orders is 1.
+    pub fn generate_from_token(
+        &self,
+        token: &str,
+        temperature: f64,
+        rng: &mut impl Rng,
+    ) -> Vec<String> {
+        let mut history = vec![None; self.order() - 1];
+        history.push(Some(token.to_owned()));
+
+        if !self.has_state(&history) {
+            return Vec::new();
+        }
+
+        self.generate_from_history(history, vec![token.to_owned()], temperature, rng)
+    }
+
+    /// Removes states and transitions containing any token that `is_blocked`
+    /// returns true for. Returns `None` for mapped models, which can't be modified.
+    pub fn remove_tokens(
+        &mut self,
+        is_blocked: impl Fn(&str) -> bool,
+    ) -> Result<Option<PruneStats>> {
+        match self {
+            Model::Chain(chain) => chain.remove_tokens(is_blocked).map(Some),
+            Model::Mapped(_) => Ok(None),
+            Model::Backoff(backoff) => {
+                let mut total = PruneStats::default();
+                for chain in backoff.chains_mut() {
+                    let stats = chain.remove_tokens(&is_blocked)?;
+                    total.states_removed += stats.states_removed;
+                    total.transitions_removed += stats.transitions_removed;
+                    total.dead_ends_repaired += stats.dead_ends_repaired;
+                }
+                Ok(Some(total))
+            }
+        }
+    }
+
+    /// Only affects models trained with multiple orders.
+    /// See `BackoffModel::min_support`.
+    pub fn set_min_support(&mut self, min_support: usize) {
+        if let Model::Backoff(backoff) = self {
+            backoff.min_support = min_support;
+        }
+    }
+}
+
+fn deserialize<T>(file: impl Read, compressed: bool) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    if compressed {
+        bincode::deserialize_from(flate2::read::GzDecoder::new(file))
+    } else {
+        bincode::deserialize_from(file)
+    }
+    .context_kind(ErrorKind::Model, || "failed to load model file")
+}
+
+/// Loads a model file, detecting the format from its first few bytes
+pub fn load_model(path: &str) -> Result<Model> {
+    let mut file = BufReader::new(File::open(path).context(|| "failed to open model file")?);
+
+    let mut header = [0; 10];
+    let is_binary = match file.read_exact(&mut header) {
+        Ok(()) => &header[..MAGIC.len()] == MAGIC,
+        // Too short to be a binary model
+        Err(_) => false,
+    };
+
+    if !is_binary {
+        file.seek(SeekFrom::Start(0))
+            .context(|| "failed to read model file")?;
+        let chain: RawChain = serde_yaml::from_reader(file)
+            .context_kind(ErrorKind::Model, || "failed to load model file")?;
+        check_orders(&[chain.order])
+            .context_kind(ErrorKind::Model, || "failed to load model file")?;
+        return Ok(Model::Chain(chain));
+    }
+
+    let version = header[MAGIC.len()];
+    if version != VERSION {
+        return Err(Error::new(
+            ErrorKind::Model,
+            format!("unsupported model file version {}", version),
+        ));
+    }
+
+    let encoding = header[MAGIC.len() + 1];
+    match encoding {
+        UNCOMPRESSED | GZIP => {
+            let chain: RawChain = deserialize(file, encoding == GZIP)?;
+            check_orders(&[chain.order])
+                .context_kind(ErrorKind::Model, || "failed to load model file")?;
+            Ok(Model::Chain(chain))
+        }
+        MAPPED => MappedModel::open(path).map(Model::Mapped),
+        BACKOFF | BACKOFF_GZIP => {
+            let chains: Vec<RawChain> = deserialize(file, encoding == BACKOFF_GZIP)?;
+            if chains.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::Model,
+                    "model file contains no chains",
+                ));
+            }
+            let orders = chains.iter().map(|chain| chain.order).collect::<Vec<_>>();
+            check_orders(&orders).context_kind(ErrorKind::Model, || "failed to load model file")?;
+            Ok(Model::Backoff(BackoffModel::new(chains)))
+        }
+        other => Err(Error::new(
+            ErrorKind::Model,
+            format!("unknown model file encoding {}", other),
+        )),
+    }
+}
+
+fn create_file(path: &str, encoding: u8) -> Result<BufWriter<File>> {
+    let mut file = BufWriter::new(File::create(path).context(|| "failed to create model file")?);
+
+    file.write_all(MAGIC)
+        .and_then(|_| file.write_all(&[VERSION, encoding]))
+        .context(|| "failed to write model file header")?;
+
+    Ok(file)
+}
+
+fn write_binary<T>(path: &str, encoding: u8, value: &T) -> Result<()>
+where
+    T: serde::Serialize + ?Sized,
+{
+    let mut file = create_file(path, encoding)?;
+
+    if encoding == GZIP || encoding == BACKOFF_GZIP {
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        bincode::serialize_into(&mut encoder, value)
+            .context_kind(ErrorKind::Io, || "failed to save model file")?;
+        encoder
+            .finish()
+            .and_then(|mut file| file.flush())
+            .context_kind(ErrorKind::Io, || "failed to save model file")
+    } else {
+        bincode::serialize_into(&mut file, value)
+            .context_kind(ErrorKind::Io, || "failed to save model file")?;
+        file.flush()
+            .context_kind(ErrorKind::Io, || "failed to save model file")
+    }
+}
+
+fn write_yaml(path: &str, chain: &RawChain) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path).context(|| "failed to create model file")?);
+
+    serde_yaml::to_writer(&mut file, chain)
+        .context_kind(ErrorKind::Io, || "failed to save model file")?;
+    file.flush()
+        .context_kind(ErrorKind::Io, || "failed to save model file")
+}
+
+fn write_mapped(path: &str, chain: &RawChain) -> Result<()> {
+    let mut file = create_file(path, MAPPED)?;
+
+    // Pad the header so the mapped layout starts at a round offset
+    file.write_all(&[0; 6])
+        .context_kind(ErrorKind::Io, || "failed to save model file")?;
+    mapped::write(chain, &mut file).context(|| "failed to save model file")?;
+    file.flush()
+        .context_kind(ErrorKind::Io, || "failed to save model file")
+}
+
+pub fn save_model(model: &Model, path: &str, format: ModelFormat) -> Result<()> {
+    let loaded;
+    let chain = match model {
+        Model::Chain(chain) => chain,
+        Model::Mapped(mapped) => {
+            loaded = mapped.to_raw()?;
+            &loaded
+        }
+        Model::Backoff(backoff) => {
+            return match format {
+                ModelFormat::Binary => write_binary(path, BACKOFF, backoff.chains()),
+                ModelFormat::Compressed => write_binary(path, BACKOFF_GZIP, backoff.chains()),
+                _ => Err(multiple_orders_error()),
+            };
+        }
+    };
+
+    match format {
+        ModelFormat::Yaml => write_yaml(path, chain),
+        ModelFormat::Binary => write_binary(path, UNCOMPRESSED, chain),
+        ModelFormat::Compressed => write_binary(path, GZIP, chain),
+        ModelFormat::Mapped => write_mapped(path, chain),
+    }
+}
+
+pub fn convert(observer: &dyn Observer, args: opts::Convert) -> Result<()> {
+    observer.info("Loading model from file", &[("path", &args.input)]);
+    let model = load_model(&args.input)?;
+
+    observer.info("Saving model to file", &[("path", &args.output)]);
+    save_model(&model, &args.output, args.to)
+}
+
+/// Builds chains and transitions for tests
+#[cfg(test)]
+pub mod test_helpers {
+    use super::RawChain;
+    use std::collections::HashMap;
+
+    /// Splits a line on spaces
+    pub fn tokens(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    /// Chain trained on space-separated lines
+    pub fn chain(order: usize, lines: &[&str]) -> RawChain {
+        let mut chain = RawChain::of_order(order);
+        for line in lines {
+            chain.feed(&tokens(line));
+        }
+        chain
+    }
+
+    pub fn transitions(pairs: &[(Option<&str>, usize)]) -> HashMap<Option<String>, usize> {
+        pairs
+            .iter()
+            .map(|&(token, count)| (token.map(String::from), count))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_helpers::{tokens, transitions};
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    /// Order 1 model where "la" is most often followed by another "la"
+    fn looping_model() -> Model {
+        let mut map = HashMap::new();
+        map.insert(
+            vec![None],
+            vec![(Some("la".to_owned()), 1)].into_iter().collect(),
+        );
+        map.insert(
+            vec![Some("la".to_owned())],
+            vec![(Some("la".to_owned()), 3), (None, 1)]
+                .into_iter()
+                .collect(),
+        );
+        Model::Chain(RawChain { map, order: 1 })
+    }
+
+    #[test]
+    fn zero_temperature_picks_most_common() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10 {
+            assert_eq!(
+                sample_index(vec![1, 5, 2].into_iter(), 0.0, &mut rng),
+                Some(1)
+            );
+        }
+    }
+
+    #[test]
+    fn chains_stop_at_max_tokens() {
+        let model = looping_model();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let tokens = model.generate(0.0, &mut rng);
+        assert_eq!(tokens.len(), MAX_CHAIN_TOKENS);
+        assert!(tokens.iter().all(|token| token == "la"));
+
+        let tokens = model.generate_from_token("la", 0.0, &mut rng);
+        assert_eq!(tokens.len(), MAX_CHAIN_TOKENS);
+    }
+
+    /// Chain of order 2, trained on "hello there" and "hello you"
+    fn expected_map() -> HashMap<Vec<Option<String>>, HashMap<Option<String>, usize>> {
+        let hello = Some("hello".to_owned());
+        let mut map = HashMap::new();
+        map.insert(vec![None, None], transitions(&[(Some("hello"), 2)]));
+        map.insert(
+            vec![None, hello.clone()],
+            transitions(&[(Some("there"), 1), (Some("you"), 1)]),
+        );
+        map.insert(
+            vec![hello.clone(), Some("there".to_owned())],
+            transitions(&[(None, 1)]),
+        );
+        map.insert(
+            vec![hello, Some("you".to_owned())],
+            transitions(&[(None, 1)]),
+        );
+        map
+    }
+
+    #[test]
+    fn feed_counts_transitions() {
+        let mut chain = RawChain::of_order(2);
+        chain.feed(&tokens("hello there"));
+        chain.feed(&tokens("hello you"));
+        chain.feed(&[]);
+        assert_eq!(chain.map, expected_map());
+    }
+
+    /// YAML models used to be saved with `markov::Chain::save` (version 1.0.2),
+    /// and have to keep loading
+    #[test]
+    fn load_yaml_saved_by_markov() {
+        let yaml = "\
+---
+map:
+  ? - hello
+    - there
+  : ~: 1
+  ? - ~
+    - ~
+  : hello: 2
+  ? - hello
+    - you
+  : ~: 1
+  ? - ~
+    - hello
+  : you: 1
+    there: 1
+order: 2";
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.yaml");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, yaml).unwrap();
+
+        match load_model(path).unwrap() {
+            Model::Chain(chain) => {
+                assert_eq!(chain.order, 2);
+                assert_eq!(chain.map, expected_map());
+            }
+            other => panic!("expected a single chain, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn save_then_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let formats = &[
+            ModelFormat::Yaml,
+            ModelFormat::Binary,
+            ModelFormat::Compressed,
+            ModelFormat::Mapped,
+        ];
+
+        for &format in formats {
+            let path = dir.path().join(format!("{:?}", format));
+            let path = path.to_str().unwrap();
+            let chain = RawChain {
+                map: expected_map(),
+                order: 2,
+            };
+            save_model(&Model::Chain(chain), path, format).unwrap();
+
+            let map = match load_model(path).unwrap() {
+                Model::Chain(chain) => chain.map,
+                Model::Mapped(mapped) => mapped.to_raw().unwrap().map,
+                other => panic!("expected a single chain, got {:?}", other),
+            };
+            assert_eq!(map, expected_map(), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn models_with_invalid_orders_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for &format in &[ModelFormat::Yaml, ModelFormat::Binary] {
+            let path = dir.path().join(format!("{:?}", format));
+            let path = path.to_str().unwrap();
+            let chain = RawChain::of_order(0);
+            save_model(&Model::Chain(chain), path, format).unwrap();
+
+            let error = load_model(path).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Model, "{:?}", format);
+        }
+
+        let path = dir.path().join("backoff");
+        let path = path.to_str().unwrap();
+        let chains = vec![RawChain::of_order(2), RawChain::of_order(2)];
+        save_model(
+            &Model::Backoff(BackoffModel::new(chains)),
+            path,
+            ModelFormat::Binary,
+        )
+        .unwrap();
+        assert_eq!(load_model(path).unwrap_err().kind, ErrorKind::Model);
+    }
+}
@@ -0,0 +1,223 @@
+use super::{BackoffModel, Model, RawChain};
+use crate::error::*;
+use crate::observer::Observer;
+use crate::opts;
+
+#[derive(Debug, Default)]
+pub struct PruneStats {
+    pub states_removed: usize,
+    pub transitions_removed: usize,
+    /// States that had no transitions left, and were changed to end the chain
+    pub dead_ends_repaired: usize,
+}
+
+impl RawChain {
+    /// Removes states and transitions that were seen fewer than `min_count`
+    /// times, then removes any transitions that would lead to a removed state.
+    pub fn prune(&mut self, min_count: usize) -> Result<PruneStats> {
+        let mut stats = PruneStats::default();
+
+        let states_before = self.map.len();
+        self.map
+            .retain(|_, transitions| transitions.values().sum::<usize>() >= min_count);
+        stats.states_removed = states_before - self.map.len();
+
+        for transitions in self.map.values_mut() {
+            let transitions_before = transitions.len();
+            transitions.retain(|_, count| *count >= min_count);
+            stats.transitions_removed += transitions_before - transitions.len();
+        }
+
+        self.repair(&mut stats)?;
+
+        Ok(stats)
+    }
+
+    /// Removes states and transitions containing any token that `is_blocked`
+    /// returns true for
+    pub fn remove_tokens(&mut self, is_blocked: impl Fn(&str) -> bool) -> Result<PruneStats> {
+        let mut stats = PruneStats::default();
+        let blocked = |token: &Option<String>| token.as_ref().map_or(false, |t| is_blocked(t));
+
+        let states_before = self.map.len();
+        self.map.retain(|state, _| !state.iter().any(&blocked));
+        stats.states_removed = states_before - self.map.len();
+
+        for transitions in self.map.values_mut() {
+            let transitions_before = transitions.len();
+            transitions.retain(|token, _| !blocked(token));
+            stats.transitions_removed += transitions_before - transitions.len();
+        }
+
+        self.repair(&mut stats)?;
+
+        Ok(stats)
+    }
+
+    /// Removes any transitions that would lead to a missing state
+    fn repair(&mut self, stats: &mut PruneStats) -> Result<()> {
+        // Following a transition that leads to a missing state would leave the
+        // generator with nowhere to go, so those transitions have to go too
+        let dead_ends = self
+            .map
+            .iter()
+            .flat_map(|(state, transitions)| {
+                transitions
+                    .keys()
+                    .filter(|token| token.is_some())
+                    .map(move |token| (state, token))
+            })
+            .filter(|&(state, token)| {
+                let mut next = state[1..].to_vec();
+                next.push(token.clone());
+                !self.map.contains_key(&next)
+            })
+            .map(|(state, token)| (state.clone(), token.clone()))
+            .collect::<Vec<_>>();
+
+        stats.transitions_removed += dead_ends.len();
+        for (state, token) in dead_ends {
+            if let Some(transitions) = self.map.get_mut(&state) {
+                transitions.remove(&token);
+            }
+        }
+
+        // States with nothing left end the chain instead. Since this doesn't
+        // refer to any other state, it can't create new dead ends.
+        for transitions in self.map.values_mut() {
+            if transitions.is_empty() {
+                transitions.insert(None, 1);
+                stats.dead_ends_repaired += 1;
+            }
+        }
+
+        if !self.map.contains_key(&vec![None; self.order]) {
+            return Err(Error::new(
+                ErrorKind::Model,
+                "every starting state was removed from the model",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Prunes every chain in a model, logging how much was removed
+pub fn prune_model(observer: &dyn Observer, model: Model, min_count: usize) -> Result<Model> {
+    let prune_raw = |mut raw: RawChain| -> Result<RawChain> {
+        let stats = raw.prune(min_count)?;
+
+        observer.info(
+            "Pruned model",
+            &[
+                ("order", &raw.order),
+                ("min_count", &min_count),
+                ("states_removed", &stats.states_removed),
+                ("transitions_removed", &stats.transitions_removed),
+                ("dead_ends_repaired", &stats.dead_ends_repaired),
+                ("states_remaining", &raw.map.len()),
+            ],
+        );
+
+        Ok(raw)
+    };
+
+    match model {
+        Model::Chain(chain) => prune_raw(chain).map(Model::Chain),
+        Model::Mapped(mapped) => prune_raw(mapped.to_raw()?).map(Model::Chain),
+        Model::Backoff(backoff) => {
+            let chains = backoff
+                .into_chains()
+                .into_iter()
+                .map(prune_raw)
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Model::Backoff(BackoffModel::new(chains)))
+        }
+    }
+}
+
+pub fn prune(observer: &dyn Observer, args: opts::Prune) -> Result<()> {
+    observer.info("Loading model from file", &[("path", &args.input)]);
+    let model = super::load_model(&args.input)?;
+
+    let model = prune_model(observer, model, args.min_count)?;
+
+    observer.info("Saving model to file", &[("path", &args.output)]);
+    super::save_model(&model, &args.output, args.model_format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::test_helpers::{chain, transitions};
+    use std::collections::HashMap;
+
+    fn state(tokens: &[Option<&str>]) -> Vec<Option<String>> {
+        tokens.iter().map(|t| t.map(String::from)).collect()
+    }
+
+    #[test]
+    fn prune_removes_rare_states_and_transitions() {
+        let mut raw = chain(1, &["a b", "a b", "a b", "a c"]);
+        let stats = raw.prune(2).unwrap();
+
+        assert_eq!(stats.states_removed, 1);
+        assert_eq!(stats.transitions_removed, 1);
+        assert_eq!(stats.dead_ends_repaired, 0);
+
+        let mut expected = HashMap::new();
+        expected.insert(state(&[None]), transitions(&[(Some("a"), 4)]));
+        expected.insert(state(&[Some("a")]), transitions(&[(Some("b"), 3)]));
+        expected.insert(state(&[Some("b")]), transitions(&[(None, 3)]));
+        assert_eq!(raw.map, expected);
+    }
+
+    #[test]
+    fn prune_fails_without_starting_states() {
+        let mut raw = chain(2, &["a b", "a c"]);
+        let error = raw.prune(3).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Model);
+    }
+
+    #[test]
+    fn remove_tokens_ends_chains_before_blocked_tokens() {
+        let mut raw = chain(2, &["a b c", "a b"]);
+        let stats = raw.remove_tokens(|token| token == "c").unwrap();
+
+        assert_eq!(stats.states_removed, 1);
+        assert_eq!(stats.transitions_removed, 1);
+        assert_eq!(stats.dead_ends_repaired, 0);
+        assert_eq!(
+            raw.map[&state(&[Some("a"), Some("b")])],
+            transitions(&[(None, 1)])
+        );
+
+        let mut raw = chain(2, &["a b c"]);
+        let stats = raw.remove_tokens(|token| token == "c").unwrap();
+        assert_eq!(stats.dead_ends_repaired, 1);
+        assert_eq!(
+            raw.map[&state(&[Some("a"), Some("b")])],
+            transitions(&[(None, 1)])
+        );
+    }
+
+    #[test]
+    fn repair_removes_transitions_to_missing_states() {
+        let mut map = HashMap::new();
+        map.insert(state(&[None]), transitions(&[(Some("a"), 2)]));
+        map.insert(
+            state(&[Some("a")]),
+            transitions(&[(Some("b"), 1), (None, 1)]),
+        );
+        map.insert(state(&[Some("c")]), transitions(&[(Some("d"), 1)]));
+        let mut raw = RawChain { map, order: 1 };
+
+        let mut stats = PruneStats::default();
+        raw.repair(&mut stats).unwrap();
+
+        assert_eq!(stats.transitions_removed, 2);
+        assert_eq!(stats.dead_ends_repaired, 1);
+        assert_eq!(raw.map[&state(&[Some("a")])], transitions(&[(None, 1)]));
+        assert_eq!(raw.map[&state(&[Some("c")])], transitions(&[(None, 1)]));
+    }
+}
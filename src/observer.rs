@@ -0,0 +1,76 @@
+use std::fmt::Display;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warning,
+    Error,
+}
+
+/// Named value describing an event, e.g. `("path", &path)`
+pub type Field<'a> = (&'static str, &'a dyn Display);
+
+/// Progress or diagnostic message emitted by a long-running operation
+pub struct Event<'a> {
+    pub level: Level,
+    pub message: &'a str,
+    pub fields: &'a [Field<'a>],
+}
+
+/// Receives events as they happen, e.g. to log them. Failures are returned as
+/// errors rather than reported as events, so ignoring every event is fine.
+///
+/// Closures taking an `&Event` can be used as observers.
+pub trait Observer: Send + Sync {
+    fn event(&self, event: &Event);
+
+    fn debug(&self, message: &str, fields: &[Field]) {
+        self.event(&Event {
+            level: Level::Debug,
+            message,
+            fields,
+        })
+    }
+
+    fn info(&self, message: &str, fields: &[Field]) {
+        self.event(&Event {
+            level: Level::Info,
+            message,
+            fields,
+        })
+    }
+
+    fn warn(&self, message: &str, fields: &[Field]) {
+        self.event(&Event {
+            level: Level::Warning,
+            message,
+            fields,
+        })
+    }
+
+    fn error(&self, message: &str, fields: &[Field]) {
+        self.event(&Event {
+            level: Level::Error,
+            message,
+            fields,
+        })
+    }
+}
+
+impl<F> Observer for F
+where
+    F: Fn(&Event) + Send + Sync,
+{
+    fn event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Ignores every event
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {
+    fn event(&self, _event: &Event) {}
+}
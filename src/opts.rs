@@ -1,4 +1,5 @@
 use crate::error::*;
+use crate::model::ModelFormat;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -19,6 +20,33 @@ pub enum Opts {
         about = "Uses ffmpeg to generate screenshots with fake subtitles"
     )]
     Screenshots(Screenshots),
+
+    #[structopt(
+        name = "render",
+        about = "Uses ffmpeg to render a whole video with fake subtitles"
+    )]
+    Render(Render),
+
+    #[structopt(
+        name = "repl",
+        about = "Loads a model and interactively generates text from it"
+    )]
+    Repl(Repl),
+
+    #[structopt(
+        name = "serve",
+        about = "Runs an HTTP server that generates text from loaded models"
+    )]
+    Serve(Serve),
+
+    #[structopt(name = "convert", about = "Converts a model file to another format")]
+    Convert(Convert),
+
+    #[structopt(
+        name = "prune",
+        about = "Removes rarely seen transitions from a model file"
+    )]
+    Prune(Prune),
 }
 
 #[derive(Debug, StructOpt)]
@@ -26,17 +54,41 @@ pub struct Train {
     #[structopt(
         long = "output",
         short = "o",
+        required_unless = "dry_run",
         help = "Output destination for the model file"
     )]
-    pub output: String,
+    pub output: Option<String>,
 
     #[structopt(
         long = "order",
         default_value = "2",
+        raw(use_delimiter = "true"),
         help = "Order of the Markov model. Higher values cause the generated \
-                text to more closely resemble the training set."
+                text to more closely resemble the training set. If multiple \
+                comma-separated orders are given (e.g., `1,2,3`), generation uses \
+                the highest order that has seen the current state often enough, \
+                and backs off to lower orders for rare states. These models can \
+                only be saved in the `binary` or `compressed` formats."
+    )]
+    pub order: Vec<usize>,
+
+    #[structopt(
+        long = "model-format",
+        default_value = "yaml",
+        raw(possible_values = "MODEL_FORMATS"),
+        help = "Format of the output model file. `binary` and `compressed` are \
+                smaller and faster to load than `yaml`. `mapped` models are read \
+                on demand instead of being loaded into memory."
+    )]
+    pub model_format: ModelFormat,
+
+    #[structopt(
+        long = "min-count",
+        default_value = "1",
+        help = "Remove transitions (and states) seen fewer than this many times \
+                in the training data. See the `prune` subcommand."
     )]
-    pub order: usize,
+    pub min_count: usize,
 
     #[structopt(
         long = "recursive",
@@ -45,6 +97,43 @@ pub struct Train {
     )]
     pub recursive: bool,
 
+    #[structopt(
+        long = "dedup",
+        default_value = "adjacent",
+        raw(possible_values = r#"&["adjacent", "exact", "near"]"#),
+        help = "How to skip duplicate lines. `adjacent` only skips a line if it's the same \
+                as the line before it. `exact` also skips lines that were already seen \
+                anywhere in the training run. `near` is like `exact`, but ignores \
+                differences in case and punctuation."
+    )]
+    pub dedup: Dedup,
+
+    #[structopt(
+        long = "jobs",
+        short = "j",
+        default_value = "1",
+        help = "Number of input files to parse and tokenize in parallel. \
+                If 0, uses one job per CPU. The resulting model is the same \
+                regardless of the number of jobs."
+    )]
+    pub jobs: usize,
+
+    #[structopt(
+        long = "dry-run",
+        help = "Instead of training a model, print which lines of each input file \
+                would be used as training data, and why the rest would be dropped"
+    )]
+    pub dry_run: bool,
+
+    #[structopt(
+        long = "report-format",
+        default_value = "text",
+        raw(possible_values = r#"&["text", "json"]"#),
+        help = "Output format for the `--dry-run` report. \
+                `json` prints one JSON object per input file, one per line."
+    )]
+    pub report_format: ReportFormat,
+
     #[structopt(
         required = true,
         help = "List of training files to use as input \
@@ -53,6 +142,240 @@ pub struct Train {
     pub input: Vec<String>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(Error::new(ErrorKind::Config, "unknown report format")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Jsonl,
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(Error::new(ErrorKind::Config, "unknown output format")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimestampStrategy {
+    Random,
+    Middle,
+    /// Offset from the start of the line, in milliseconds
+    Start(u32),
+    SceneAware,
+}
+
+impl std::str::FromStr for TimestampStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "random" => Ok(TimestampStrategy::Random),
+            "middle" => Ok(TimestampStrategy::Middle),
+            "start" => Ok(TimestampStrategy::Start(0)),
+            "scene-aware" => Ok(TimestampStrategy::SceneAware),
+            _ if s.starts_with("start+") => {
+                parse_duration_ms(&s["start+".len()..]).map(TimestampStrategy::Start)
+            }
+            _ => Err(Error::new(ErrorKind::Config, "unknown timestamp strategy")),
+        }
+    }
+}
+
+impl std::fmt::Display for TimestampStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TimestampStrategy::Random => write!(f, "random"),
+            TimestampStrategy::Middle => write!(f, "middle"),
+            TimestampStrategy::Start(offset_ms) => write!(f, "start+{}ms", offset_ms),
+            TimestampStrategy::SceneAware => write!(f, "scene-aware"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Jpg,
+    Png,
+    Webp,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Jpg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+        }
+    }
+}
+
+impl std::str::FromStr for ImageFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jpg" | "jpeg" => Ok(ImageFormat::Jpg),
+            "png" => Ok(ImageFormat::Png),
+            "webp" => Ok(ImageFormat::Webp),
+            _ => Err(Error::new(ErrorKind::Config, "unknown image format")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClipFormat {
+    Gif,
+    Webm,
+    Mp4,
+}
+
+impl ClipFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ClipFormat::Gif => "gif",
+            ClipFormat::Webm => "webm",
+            ClipFormat::Mp4 => "mp4",
+        }
+    }
+}
+
+impl std::str::FromStr for ClipFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gif" => Ok(ClipFormat::Gif),
+            "webm" => Ok(ClipFormat::Webm),
+            "mp4" => Ok(ClipFormat::Mp4),
+            _ => Err(Error::new(ErrorKind::Config, "unknown clip format")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Renderer {
+    Ffmpeg,
+    Fake,
+}
+
+impl std::str::FromStr for Renderer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ffmpeg" => Ok(Renderer::Ffmpeg),
+            "fake" => Ok(Renderer::Fake),
+            _ => Err(Error::new(ErrorKind::Config, "unknown renderer")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dedup {
+    Adjacent,
+    Exact,
+    Near,
+}
+
+impl std::str::FromStr for Dedup {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "adjacent" => Ok(Dedup::Adjacent),
+            "exact" => Ok(Dedup::Exact),
+            "near" => Ok(Dedup::Near),
+            _ => Err(Error::new(ErrorKind::Config, "unknown dedup mode")),
+        }
+    }
+}
+
+/// Options for generating lines, shared by every subcommand that generates them.
+/// `serve` uses them as defaults for requests, and `repl` as initial settings.
+#[derive(Debug, StructOpt)]
+pub struct GenerationOpts {
+    #[structopt(
+        long = "min-length",
+        help = "Ensure that generated chains have at least this many characters"
+    )]
+    pub min_length: Option<usize>,
+
+    #[structopt(
+        long = "min-support",
+        default_value = "2",
+        help = "For models trained with multiple orders, the minimum number of times \
+                a state must have been seen for its order to be used, before \
+                backing off to a lower order"
+    )]
+    pub min_support: usize,
+
+    #[structopt(
+        long = "temperature",
+        parse(try_from_str = "parse_temperature"),
+        default_value = "1.0",
+        help = "Values below 1 favor common phrases (0 always picks the most common \
+                next word), while values above 1 make rare words more likely, for \
+                more chaotic output"
+    )]
+    pub temperature: f64,
+
+    #[structopt(
+        long = "candidates",
+        default_value = "1",
+        help = "Generate this many candidates for each line, and keep the one that \
+                scores best on probability under the model, length, branching, \
+                and balanced brackets"
+    )]
+    pub candidates: usize,
+
+    #[structopt(
+        long = "blocklist",
+        help = "File with one blocked word or phrase per line (or a regex, if \
+                surrounded by slashes, e.g. `/fo+/`). Generated lines containing a \
+                blocked word or phrase, or matching a blocked pattern, are regenerated."
+    )]
+    pub blocklist: Option<String>,
+
+    #[structopt(
+        long = "max-retries",
+        default_value = "10",
+        help = "Maximum number of times to regenerate a line blocked by `--blocklist`"
+    )]
+    pub max_retries: usize,
+
+    #[structopt(
+        long = "remove-blocked-tokens",
+        help = "Remove words in the `--blocklist` from the model when it's loaded, \
+                so they're never generated (not supported for mapped models)"
+    )]
+    pub remove_blocked_tokens: bool,
+}
+
 #[derive(Debug, StructOpt)]
 pub struct Generate {
     #[structopt(help = "Path to a model file generated from the training phase")]
@@ -70,15 +393,32 @@ pub struct Generate {
         long = "start-token",
         alias = "start",
         help = "Generate chains starting with this token. \
-                Note that this will only work if the model was trained with order = 1."
+                Note that this will only work if the model was trained with order = 1, \
+                or with multiple orders including 1."
     )]
     pub start: Option<String>,
 
+    #[structopt(flatten)]
+    pub generation: GenerationOpts,
+
     #[structopt(
-        long = "min-length",
-        help = "Ensure that generated chains have at least this many characters"
+        long = "seed",
+        help = "Seed for the random number generator. Each generated line uses \
+                the next seed after the previous line's, so a line can be \
+                reproduced with `-n 1 --seed <its seed>` and the same options."
     )]
-    pub min_length: Option<usize>,
+    pub seed: Option<u64>,
+
+    #[structopt(
+        long = "output-format",
+        default_value = "text",
+        raw(possible_values = r#"&["text", "jsonl", "json"]"#),
+        help = "Format of the generated lines. `jsonl` prints one JSON object per \
+                line, and `json` prints a single JSON array. Each object contains \
+                the text, tokens, start token, number of retries, and seed. \
+                Ignored when using `existing-subs`."
+    )]
+    pub output_format: OutputFormat,
 
     #[structopt(
         long = "existing-subs",
@@ -91,15 +431,50 @@ pub struct Generate {
 }
 
 #[derive(Debug, StructOpt)]
-pub struct Screenshots {
-    #[structopt(long = "model", help = "Trained model")]
+pub struct Repl {
+    #[structopt(help = "Path to a model file generated from the training phase")]
     pub model: String,
 
+    #[structopt(flatten)]
+    pub generation: GenerationOpts,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Serve {
     #[structopt(
-        long = "min-length",
-        help = "Ensure that generated chains have at least this many characters"
+        long = "address",
+        default_value = "127.0.0.1:8080",
+        help = "Address to listen on"
     )]
-    pub min_length: Option<usize>,
+    pub address: String,
+
+    #[structopt(flatten)]
+    pub generation: GenerationOpts,
+
+    #[structopt(
+        long = "jobs",
+        short = "j",
+        default_value = "0",
+        help = "Number of requests to handle in parallel. If 0, uses one job per CPU."
+    )]
+    pub jobs: usize,
+
+    #[structopt(
+        required = true,
+        help = "Model files to load, as `PATH` or `NAME=PATH`. If the name is \
+                omitted, the file name without its extension is used. Requests \
+                that don't specify a model use the first one."
+    )]
+    pub models: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Screenshots {
+    #[structopt(long = "model", help = "Trained model")]
+    pub model: String,
+
+    #[structopt(flatten)]
+    pub generation: GenerationOpts,
 
     #[structopt(long = "video", help = "Input video file")]
     pub video: String,
@@ -152,8 +527,272 @@ pub struct Screenshots {
         help = "Resolution. I.e., 200ms means take a maximum of one screenshot every 200 ms"
     )]
     pub resolution_ms: u32,
+
+    #[structopt(
+        long = "timestamp-strategy",
+        default_value = "random",
+        help = "How to pick the moment of each line to take a screenshot of: \
+                `random`, `middle`, `start+OFFSET` (e.g., `start+500ms`), or \
+                `scene-aware`, which avoids frames near scene changes (detected by \
+                decoding the whole video) and frames where the line is fading in or \
+                out (according to its `\\fad` tag). Ignored for clips."
+    )]
+    pub timestamp_strategy: TimestampStrategy,
+
+    #[structopt(
+        long = "image-format",
+        default_value = "jpg",
+        raw(possible_values = r#"&["jpg", "png", "webp"]"#),
+        help = "Image format of the screenshots"
+    )]
+    pub image_format: ImageFormat,
+
+    #[structopt(
+        long = "quality",
+        parse(try_from_str = "parse_quality"),
+        help = "Image quality, from 1 to 100 (higher is better). Ignored for `png`, \
+                which is lossless. If unspecified, ffmpeg's default is used."
+    )]
+    pub quality: Option<u32>,
+
+    #[structopt(
+        long = "scale",
+        parse(try_from_str = "parse_size"),
+        conflicts_with = "max_width",
+        help = "Resize screenshots to WIDTHxHEIGHT (e.g., `1280x720`). If either is \
+                -1, it's calculated from the other to keep the aspect ratio."
+    )]
+    pub scale: Option<(i32, i32)>,
+
+    #[structopt(
+        long = "max-width",
+        help = "Shrink screenshots wider than this, keeping the aspect ratio"
+    )]
+    pub max_width: Option<u32>,
+
+    #[structopt(
+        long = "clip",
+        help = "Save a clip of the whole span of each line, instead of a single frame"
+    )]
+    pub clip: bool,
+
+    #[structopt(
+        long = "clip-format",
+        requires = "clip",
+        raw(possible_values = r#"&["gif", "webm", "mp4"]"#),
+        help = "Format of clips saved with `--clip` [default: mp4]"
+    )]
+    pub clip_format: Option<ClipFormat>,
+
+    #[structopt(
+        long = "padding",
+        parse(try_from_str = "parse_duration_ms"),
+        requires = "clip",
+        help = "Time to include before and after each line in clips (e.g., `500ms`) \
+                [default: 0]"
+    )]
+    pub padding_ms: Option<u32>,
+
+    #[structopt(
+        long = "audio",
+        requires = "clip",
+        help = "Include audio in clips (not supported for `gif`)"
+    )]
+    pub audio: bool,
+
+    #[structopt(long = "fps", requires = "clip", help = "Frame rate of clips")]
+    pub fps: Option<u32>,
+
+    #[structopt(
+        long = "max-size",
+        requires = "clip",
+        parse(try_from_str = "parse_bytes"),
+        help = "Maximum file size of each clip (e.g., `8M`). The bitrate of `webm` \
+                and `mp4` clips is lowered to fit. Clips that are still too large \
+                fail, so use `--fps` and `--max-width` to shrink GIFs."
+    )]
+    pub max_size: Option<u64>,
+
+    #[structopt(
+        long = "seed",
+        help = "Seed for generating lines and picking screenshot timestamps. \
+                If unspecified, a random seed is used. The seed is saved in the manifest."
+    )]
+    pub seed: Option<u64>,
+
+    #[structopt(
+        long = "manifest",
+        help = "Path to save the manifest to. It lists the options used and the \
+                timestamp, path, text, and replaced line of every screenshot. \
+                Defaults to `manifest.json` in the output directory."
+    )]
+    pub manifest: Option<PathBuf>,
+
+    #[structopt(
+        long = "resume",
+        requires = "subtitles_out",
+        help = "Resume an interrupted run. Reuses the subtitles saved to \
+                `--subtitles-out` and the seed in the manifest, and only saves \
+                screenshots that aren't in the manifest yet. Other options must \
+                match the original run."
+    )]
+    pub resume: bool,
+
+    #[structopt(
+        long = "jobs",
+        short = "j",
+        default_value = "1",
+        help = "Number of screenshots to save in parallel. If 0, uses one job per CPU."
+    )]
+    pub jobs: usize,
+
+    #[structopt(
+        long = "batch",
+        help = "Save all screenshots with a single ffmpeg process. This decodes \
+                the whole video once, so it's faster when saving many screenshots \
+                (e.g., with `--all`). If it fails, screenshots are saved one at a time."
+    )]
+    pub batch: bool,
+
+    #[structopt(
+        long = "ffmpeg-path",
+        default_value = "ffmpeg",
+        help = "Path to the ffmpeg binary"
+    )]
+    pub ffmpeg_path: String,
+
+    #[structopt(
+        long = "ffprobe-path",
+        default_value = "ffprobe",
+        help = "Path to the ffprobe binary"
+    )]
+    pub ffprobe_path: String,
+
+    #[structopt(
+        long = "renderer",
+        default_value = "ffmpeg",
+        raw(possible_values = r#"&["ffmpeg", "fake"]"#, hidden = "true"),
+        help = "How to render screenshots. `fake` writes a line of text describing \
+                each frame or clip instead of an image or video (the file extension \
+                is kept), so the rest of the command can be tested without ffmpeg."
+    )]
+    pub renderer: Renderer,
 }
 
+// Options that require `--clip` can't have default values, which would count
+// as being given
+impl Screenshots {
+    pub fn clip_format(&self) -> ClipFormat {
+        self.clip_format.unwrap_or(ClipFormat::Mp4)
+    }
+
+    pub fn padding_ms(&self) -> u32 {
+        self.padding_ms.unwrap_or(0)
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Render {
+    #[structopt(long = "model", help = "Trained model")]
+    pub model: String,
+
+    #[structopt(flatten)]
+    pub generation: GenerationOpts,
+
+    #[structopt(
+        long = "seed",
+        help = "Seed for generating lines. If unspecified, a random seed is used."
+    )]
+    pub seed: Option<u64>,
+
+    #[structopt(long = "video", help = "Input video file")]
+    pub video: String,
+
+    #[structopt(
+        long = "subtitles-ref",
+        help = "Reference subtitle file (for timing/styles).\
+                If unspecified, will attempt to extract subtitles from the video file."
+    )]
+    pub subtitles_ref: Option<String>,
+
+    #[structopt(
+        long = "subtitles-out",
+        help = "Path to save the generated subtitles to, in addition to the video"
+    )]
+    pub subtitles_out: Option<String>,
+
+    #[structopt(
+        long = "output",
+        short = "o",
+        help = "Output video file. Must be `.mkv` unless `--hardsub` is used."
+    )]
+    pub output: String,
+
+    #[structopt(
+        long = "hardsub",
+        help = "Burn the subtitles into the video, instead of adding them as a \
+                subtitle track. This re-encodes the video, so it's much slower."
+    )]
+    pub hardsub: bool,
+
+    #[structopt(
+        long = "ffmpeg-path",
+        default_value = "ffmpeg",
+        help = "Path to the ffmpeg binary"
+    )]
+    pub ffmpeg_path: String,
+
+    #[structopt(
+        long = "ffprobe-path",
+        default_value = "ffprobe",
+        help = "Path to the ffprobe binary"
+    )]
+    pub ffprobe_path: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Convert {
+    #[structopt(
+        long = "to",
+        raw(possible_values = "MODEL_FORMATS"),
+        help = "Format to convert the model to"
+    )]
+    pub to: ModelFormat,
+
+    #[structopt(help = "Path to an existing model file (the format is detected automatically)")]
+    pub input: String,
+
+    #[structopt(help = "Output destination for the converted model file")]
+    pub output: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Prune {
+    #[structopt(
+        long = "min-count",
+        help = "Remove transitions (and states) seen fewer than this many times \
+                in the training data. Higher values make the model smaller and \
+                less likely to copy lines verbatim."
+    )]
+    pub min_count: usize,
+
+    #[structopt(
+        long = "model-format",
+        default_value = "yaml",
+        raw(possible_values = "MODEL_FORMATS"),
+        help = "Format of the output model file"
+    )]
+    pub model_format: ModelFormat,
+
+    #[structopt(help = "Path to an existing model file")]
+    pub input: String,
+
+    #[structopt(help = "Output destination for the pruned model file")]
+    pub output: String,
+}
+
+const MODEL_FORMATS: &[&str] = &["yaml", "binary", "compressed", "mapped"];
+
 fn parse_duration_ms(s: &str) -> Result<u32> {
     fn trim(s: &str, suffix: &str, multiplier: u32) -> Option<u32> {
         if s.ends_with(suffix) {
@@ -172,5 +811,111 @@ fn parse_duration_ms(s: &str) -> Result<u32> {
         .or_else(|| trim(s, "s", 1_000))
         .or_else(|| trim(s, "m", 60_000))
         .or_else(|| trim(s, "h", 3_600_000))
-        .ok_or_else(|| Error::context("failed to parse duration"))
+        .ok_or_else(|| Error::new(ErrorKind::Config, "failed to parse duration"))
+}
+
+pub fn parse_temperature(s: &str) -> Result<f64> {
+    s.parse::<f64>()
+        .context_kind(ErrorKind::Config, || "failed to parse temperature")
+        .and_then(crate::generate::check_temperature)
+}
+
+fn parse_quality(s: &str) -> Result<u32> {
+    match s.parse::<u32>() {
+        Ok(quality) if quality >= 1 && quality <= 100 => Ok(quality),
+        _ => Err(Error::new(
+            ErrorKind::Config,
+            "quality must be a number from 1 to 100",
+        )),
+    }
+}
+
+fn parse_size(s: &str) -> Result<(i32, i32)> {
+    let error = || {
+        Error::new(
+            ErrorKind::Config,
+            "failed to parse size (expected WIDTHxHEIGHT)",
+        )
+    };
+    let mut parts = s.splitn(2, 'x');
+    let (width, height) = match (parts.next(), parts.next()) {
+        (Some(width), Some(height)) => (width, height),
+        _ => return Err(error()),
+    };
+
+    let width = width.parse::<i32>().map_err(|_| error())?;
+    let height = height.parse::<i32>().map_err(|_| error())?;
+    let valid = |n: i32| n > 0 || n == -1;
+    if !valid(width) || !valid(height) || (width == -1 && height == -1) {
+        return Err(error());
+    }
+
+    Ok((width, height))
+}
+
+/// Parses a number of bytes, with an optional `K`, `M`, or `G` suffix
+/// (powers of 1024)
+fn parse_bytes(s: &str) -> Result<u64> {
+    let (number, multiplier) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .map(|n| n * multiplier)
+        .ok_or_else(|| Error::new(ErrorKind::Config, "failed to parse size"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temperatures_must_not_be_negative() {
+        assert_eq!(parse_temperature("0").ok(), Some(0.0));
+        assert_eq!(parse_temperature("1.5").ok(), Some(1.5));
+        for invalid in &["-0.5", "-inf", "inf", "NaN", "warm"] {
+            assert!(parse_temperature(invalid).is_err(), "{}", invalid);
+        }
+
+        let args = &["subkatsu", "generate", "--temperature=-1", "model.yaml"];
+        assert!(Opts::from_iter_safe(args).is_err());
+    }
+
+    #[test]
+    fn quality_is_from_1_to_100() {
+        assert_eq!(parse_quality("1").ok(), Some(1));
+        assert_eq!(parse_quality("100").ok(), Some(100));
+        for invalid in &["0", "101", "-1", "high"] {
+            assert!(parse_quality(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn clip_options_require_clip() {
+        let base = &[
+            "subkatsu",
+            "screenshots",
+            "--model=model.yaml",
+            "--video=video.mkv",
+            "--output-dir=out",
+        ];
+        let parse = |args: &[&str]| Opts::from_iter_safe(base.iter().chain(args));
+
+        assert!(parse(&[]).is_ok());
+        for option in &[
+            "--clip-format=gif",
+            "--padding=500ms",
+            "--audio",
+            "--fps=10",
+        ] {
+            assert!(parse(&[option]).is_err(), "{}", option);
+            assert!(parse(&["--clip", option]).is_ok(), "{}", option);
+        }
+    }
 }
@@ -0,0 +1,309 @@
+use crate::error::*;
+use crate::filter::ContentFilter;
+use crate::generate::Generator;
+use crate::model::Model;
+use crate::observer::Observer;
+use crate::opts;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+
+/// Number of successors shown by the `successors` command
+const MAX_SUCCESSORS_SHOWN: usize = 20;
+
+const HELP: &str = "\
+Commands:
+  generate [N]           Generate N lines (default 1)
+  start [TOKEN]          Start lines with TOKEN (or clear the start token)
+  min-length [N]         Generate lines with at least N characters (or clear)
+  max-length [N]         Generate lines with at most N characters (or clear)
+  temperature T          Set the sampling temperature
+  seed [N|off]           Generate from seed N each time, or use random seeds.
+                         Toggles between the two if omitted.
+  successors [TOKEN...]  Show the tokens that can follow a state, with probabilities.
+                         States shorter than the model's order are padded with the
+                         start of the line.
+  settings               Show the current settings
+  help                   Show this message
+  quit                   Exit
+";
+
+struct Settings {
+    start: Option<String>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    temperature: f64,
+    candidates: usize,
+    filter: ContentFilter,
+    seed: Option<u64>,
+}
+
+/// Loads the model once, then runs commands read from `input` until it ends or
+/// `quit` is entered. See `HELP` for the list of commands.
+pub fn repl(
+    observer: &dyn Observer,
+    args: opts::Repl,
+    input: impl BufRead,
+    output: &mut impl Write,
+) -> Result<()> {
+    let (model, filter) =
+        crate::generate::load_model_from_opts(observer, &args.model, &args.generation)?;
+    let model = Arc::new(model);
+
+    let mut settings = Settings {
+        start: None,
+        min_length: args.generation.min_length,
+        max_length: None,
+        temperature: args.generation.temperature,
+        candidates: args.generation.candidates,
+        filter,
+        seed: None,
+    };
+
+    writeln!(
+        output,
+        "Loaded model (order {}). Type `help` for a list of commands.",
+        model.order()
+    )
+    .context(|| "failed to write to output")?;
+
+    let mut lines = input.lines();
+    loop {
+        write!(output, "> ").context(|| "failed to write to output")?;
+        output.flush().context(|| "failed to write to output")?;
+
+        let line = match lines.next() {
+            Some(line) => line.context(|| "failed to read input")?,
+            None => {
+                // Move the shell prompt off the REPL prompt's line (e.g., after Ctrl+D)
+                writeln!(output).context(|| "failed to write to output")?;
+                break;
+            }
+        };
+
+        let words = line.split_whitespace().collect::<Vec<&str>>();
+        let (command, params) = match words.split_first() {
+            Some((command, params)) => (*command, params),
+            None => continue,
+        };
+
+        if command == "quit" || command == "exit" {
+            break;
+        }
+
+        // Errors from a single command shouldn't end the session
+        if let Err(err) = run_command(&model, &mut settings, command, params, output) {
+            let message = err
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join(": ");
+            writeln!(output, "error: {}", message).context(|| "failed to write to output")?;
+        }
+    }
+
+    Ok(())
+}
+
+fn run_command(
+    model: &Arc<Model>,
+    settings: &mut Settings,
+    command: &str,
+    params: &[&str],
+    output: &mut impl Write,
+) -> Result<()> {
+    match command {
+        "generate" | "g" => {
+            let count = match params.first() {
+                Some(count) => parse_param(count)?,
+                None => 1,
+            };
+            generate(model, settings, count, output)
+        }
+        "start" => {
+            settings.start = params.first().map(|token| token.to_string());
+            Ok(())
+        }
+        "min-length" => {
+            settings.min_length = parse_optional_param(params)?;
+            Ok(())
+        }
+        "max-length" => {
+            settings.max_length = parse_optional_param(params)?;
+            Ok(())
+        }
+        "temperature" => {
+            settings.temperature = match params.first() {
+                Some(temperature) => opts::parse_temperature(temperature)?,
+                None => return Err(Error::context("missing temperature")),
+            };
+            Ok(())
+        }
+        "seed" => {
+            settings.seed = match params.first() {
+                Some(&"off") => None,
+                Some(seed) => Some(parse_param(seed)?),
+                None => match settings.seed {
+                    Some(_) => None,
+                    None => Some(rand::random()),
+                },
+            };
+            show_settings(settings, output)
+        }
+        "successors" => show_successors(model, params, output),
+        "settings" => show_settings(settings, output),
+        "help" => write!(output, "{}", HELP).context(|| "failed to write to output"),
+        _ => Err(Error::context(format!(
+            "unknown command `{}` (type `help` for a list of commands)",
+            command
+        ))),
+    }
+}
+
+fn parse_param<T: std::str::FromStr>(param: &str) -> Result<T> {
+    param
+        .parse()
+        .map_err(|_| Error::context(format!("invalid value: {}", param)))
+}
+
+/// Parses the first param, or returns `None` if it's missing or `off`
+fn parse_optional_param<T: std::str::FromStr>(params: &[&str]) -> Result<Option<T>> {
+    match params.first() {
+        None | Some(&"off") => Ok(None),
+        Some(param) => parse_param(param).map(Some),
+    }
+}
+
+fn generate(
+    model: &Arc<Model>,
+    settings: &Settings,
+    count: usize,
+    output: &mut impl Write,
+) -> Result<()> {
+    let mut generator = Generator::builder()
+        .model(model.clone())
+        .start_token(settings.start.clone())
+        .min_length(settings.min_length)
+        .max_length(settings.max_length)
+        .temperature(settings.temperature)
+        .candidates(settings.candidates)
+        .filter(settings.filter.clone())
+        .seed(settings.seed)
+        .build()?;
+
+    for line in generator.lines().take(count) {
+        let line = line?;
+        writeln!(output, "[{}] {}", line.seed, line.text)
+            .context(|| "failed to write to output")?;
+    }
+
+    Ok(())
+}
+
+fn show_successors(model: &Model, params: &[&str], output: &mut impl Write) -> Result<()> {
+    let order = model.order();
+    if params.len() > order {
+        return Err(Error::context(format!(
+            "a state has at most {} tokens for this model",
+            order
+        )));
+    }
+
+    let mut history = vec![None; order - params.len()];
+    history.extend(params.iter().map(|token| Some(token.to_string())));
+
+    let successors = match model.successors(&history) {
+        Some(successors) => successors,
+        None => return Err(Error::context("state not found in model")),
+    };
+
+    let total = successors.iter().map(|(_, count)| count).sum::<usize>();
+
+    for (token, count) in successors.iter().take(MAX_SUCCESSORS_SHOWN) {
+        let probability = *count as f64 / total as f64 * 100.0;
+        let token = token.as_ref().map_or("<end>", |t| t.as_str());
+        writeln!(output, "{:>7.2}% {:>8}  {}", probability, count, token)
+            .context(|| "failed to write to output")?;
+    }
+
+    if successors.len() > MAX_SUCCESSORS_SHOWN {
+        writeln!(
+            output,
+            "... and {} more",
+            successors.len() - MAX_SUCCESSORS_SHOWN
+        )
+        .context(|| "failed to write to output")?;
+    }
+
+    Ok(())
+}
+
+fn show_settings(settings: &Settings, output: &mut impl Write) -> Result<()> {
+    fn show<T: std::fmt::Display>(value: &Option<T>) -> String {
+        value
+            .as_ref()
+            .map_or("off".to_owned(), |value| value.to_string())
+    }
+
+    writeln!(
+        output,
+        "start: {}, min-length: {}, max-length: {}, temperature: {}, seed: {}",
+        show(&settings.start),
+        show(&settings.min_length),
+        show(&settings.max_length),
+        settings.temperature,
+        show(&settings.seed)
+    )
+    .context(|| "failed to write to output")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{ModelFormat, RawChain};
+    use std::io::Cursor;
+    use structopt::StructOpt;
+
+    #[test]
+    fn commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.yaml");
+        let path = path.to_str().unwrap();
+        let mut chain = RawChain::of_order(1);
+        chain.feed(&["hello".to_owned(), "world".to_owned()]);
+        chain.feed(&["hello".to_owned(), "there".to_owned()]);
+        crate::save_model(&Model::Chain(chain), path, ModelFormat::Yaml).unwrap();
+
+        let args = opts::Repl::from_iter(&["repl", path]);
+        let input = "generate 2\nstart hello\nseed 1\ngenerate\nsuccessors hello\nfrobnicate\nquit\ngenerate\n";
+        let mut output = Vec::new();
+        repl(&crate::NoopObserver, args, Cursor::new(input), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        // Each prompt is followed by the command's output
+        let responses = output.split("> ").collect::<Vec<_>>();
+        assert_eq!(
+            responses[0],
+            "Loaded model (order 1). Type `help` for a list of commands.\n"
+        );
+
+        let generated = responses[1].lines().collect::<Vec<_>>();
+        assert_eq!(generated.len(), 2);
+        assert!(generated.iter().all(|line| line.starts_with('[')));
+
+        assert_eq!(responses[2], "");
+        assert_eq!(
+            responses[3],
+            "start: hello, min-length: off, max-length: off, temperature: 1, seed: 1\n"
+        );
+        assert!(responses[4].starts_with("[1] hello "));
+        assert_eq!(
+            responses[5],
+            "  50.00%        1  there\n  50.00%        1  world\n"
+        );
+        assert!(responses[6].starts_with("error: unknown command `frobnicate`"));
+
+        // Nothing is read after `quit`
+        assert_eq!(responses.len(), 8);
+        assert_eq!(responses[7], "");
+    }
+}
@@ -0,0 +1,196 @@
+use crate::model::Model;
+use serde_derive::{Deserialize, Serialize};
+
+/// Natural log of the probability given to transitions the model has never
+/// seen, e.g. the end of a chain cut off at the maximum length, or a token that
+/// was removed from the model after the line was generated
+const UNSEEN_LOG_PROBABILITY: f64 = -20.0;
+
+/// Quality of a generated line. Each component is between 0 and 1 (except
+/// `log_probability`), where higher is better.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Score {
+    /// Average natural log of the probability of each transition in the line
+    pub log_probability: f64,
+    /// How close the line's length is to the length of the line it replaces.
+    /// `None` if there is no length to compare to.
+    pub length_fit: Option<f64>,
+    /// Fraction of transitions where the model had more than one option. Lines
+    /// where no transition had a choice were copied verbatim from the training
+    /// data, but lines with some choices can still happen to match a training
+    /// line (which the model doesn't keep to compare to).
+    pub branching: f64,
+    /// Lower if brackets or quotes had to be added to balance the line
+    pub bracket_balance: f64,
+    /// Average of the other components, using the geometric mean of the
+    /// transition probabilities in place of `log_probability`
+    pub total: f64,
+}
+
+/// Tokens of a single chain generated by the model
+pub struct GeneratedChain<'a> {
+    pub tokens: &'a [String],
+    /// Whether the first token was given as the starting token
+    pub from_start_token: bool,
+}
+
+pub fn score<'a>(
+    model: &Model,
+    chains: impl IntoIterator<Item = GeneratedChain<'a>>,
+    length: usize,
+    target_length: Option<usize>,
+    added_symbols: usize,
+) -> Score {
+    let mut log_probability_sum = 0.0;
+    let mut steps = 0;
+    let mut choices = 0;
+
+    for chain in chains {
+        let mut history = vec![None; model.order()];
+        let mut tokens = chain.tokens;
+
+        if chain.from_start_token && !tokens.is_empty() {
+            history.remove(0);
+            history.push(Some(tokens[0].clone()));
+            tokens = &tokens[1..];
+        }
+
+        // Include the transition to the end of the chain
+        let steps_iter = tokens.iter().map(Some).chain(std::iter::once(None));
+
+        for next in steps_iter {
+            match model.transition_counts(&history, next.map(String::as_str)) {
+                Some(ref counts) if counts.count > 0 && counts.total > 0 => {
+                    log_probability_sum += (counts.count as f64 / counts.total as f64).ln();
+                    if counts.successors > 1 {
+                        choices += 1;
+                    }
+                }
+                _ => log_probability_sum += UNSEEN_LOG_PROBABILITY,
+            }
+            steps += 1;
+
+            if let Some(token) = next {
+                history.remove(0);
+                history.push(Some(token.clone()));
+            }
+        }
+    }
+
+    let (log_probability, branching) = if steps > 0 {
+        (
+            log_probability_sum / steps as f64,
+            choices as f64 / steps as f64,
+        )
+    } else {
+        (std::f64::NEG_INFINITY, 0.0)
+    };
+
+    let length_fit = target_length.map(|target| {
+        let longest = length.max(target).max(1) as f64;
+        1.0 - (length as f64 - target as f64).abs() / longest
+    });
+
+    let bracket_balance = 1.0 / (1.0 + added_symbols as f64);
+
+    let components = [
+        Some(log_probability.exp()),
+        length_fit,
+        Some(branching),
+        Some(bracket_balance),
+    ];
+    let total =
+        components.iter().flatten().sum::<f64>() / components.iter().flatten().count() as f64;
+
+    Score {
+        log_probability,
+        length_fit,
+        branching,
+        bracket_balance,
+        total,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::test_helpers::{chain, tokens};
+
+    fn model(lines: &[&str]) -> Model {
+        Model::Chain(chain(1, lines))
+    }
+
+    fn score_line(model: &Model, line: &str, target_length: Option<usize>) -> Score {
+        let tokens = tokens(line);
+        let chain = GeneratedChain {
+            tokens: &tokens,
+            from_start_token: false,
+        };
+        score(model, Some(chain), line.len(), target_length, 0)
+    }
+
+    #[test]
+    fn lines_without_choices_have_no_branching() {
+        let model = model(&["a b c"]);
+        let score = score_line(&model, "a b c", None);
+        assert_eq!(score.branching, 0.0);
+        assert_eq!(score.log_probability, 0.0);
+    }
+
+    #[test]
+    fn only_uncertain_transitions_count() {
+        let model = model(&["a b c", "x y"]);
+        let score = score_line(&model, "a b c", None);
+        // Only the first of the four transitions had a choice
+        assert_eq!(score.branching, 0.25);
+        assert!((score.log_probability - (0.5f64).ln() / 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn lines_with_choices_have_some_branching() {
+        let model = model(&["a b c", "x b y"]);
+        let score = score_line(&model, "a b y", None);
+        assert_eq!(score.branching, 0.5);
+    }
+
+    #[test]
+    fn unseen_transitions_are_penalized() {
+        let model = model(&["a b c"]);
+        // "b" was never at the end of a line, and "x" was never seen at all
+        for line in &["a b", "a x c"] {
+            let score = score_line(&model, line, None);
+            assert!(
+                score.log_probability <= UNSEEN_LOG_PROBABILITY / 4.0,
+                "{}",
+                line
+            );
+            assert!(score.total < score_line(&model, "a b c", None).total);
+        }
+    }
+
+    #[test]
+    fn length_fit_needs_a_target() {
+        let model = model(&["a b c"]);
+        assert_eq!(score_line(&model, "a b c", None).length_fit, None);
+        assert_eq!(score_line(&model, "a b c", Some(5)).length_fit, Some(1.0));
+        assert_eq!(score_line(&model, "a b c", Some(10)).length_fit, Some(0.5));
+    }
+
+    #[test]
+    fn added_symbols_lower_bracket_balance() {
+        let model = model(&["a b c"]);
+        let tokens = tokens("a b c");
+        let chain = || GeneratedChain {
+            tokens: &tokens,
+            from_start_token: false,
+        };
+        assert_eq!(
+            score(&model, Some(chain()), 5, None, 0).bracket_balance,
+            1.0
+        );
+        assert_eq!(
+            score(&model, Some(chain()), 5, None, 1).bracket_balance,
+            0.5
+        );
+    }
+}
@@ -0,0 +1,76 @@
+use super::ffmpeg::Ffmpeg;
+use crate::error::*;
+use crate::observer::Observer;
+use crate::opts::Render;
+use std::path::Path;
+
+/// Generates subtitles for a whole video, then saves a copy of the video with
+/// them as its only subtitle track, or burned in with `--hardsub`
+pub fn render_episode(observer: &dyn Observer, opts: Render) -> Result<()> {
+    let is_mkv = Path::new(&opts.output)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("mkv"));
+    if !opts.hardsub && !is_mkv {
+        return Err(Error::new(
+            ErrorKind::Config,
+            "output must be an .mkv file (or use --hardsub)",
+        ));
+    }
+
+    let ffmpeg = Ffmpeg::new(opts.ffmpeg_path.clone(), opts.ffprobe_path.clone());
+
+    let (bytes, format) = super::read_reference_subtitles(
+        observer,
+        &ffmpeg,
+        opts.subtitles_ref.as_ref().map(|s| s.as_ref()),
+        &opts.video,
+    )?;
+    let mut subtitles = crate::parse_subtitles(&mut bytes.as_slice(), format, true)?;
+
+    let builder = crate::generate::builder_from_opts(observer, &opts.model, &opts.generation)?;
+
+    let seed = opts.seed.unwrap_or_else(rand::random);
+    observer.info("Generating subtitles", &[("seed", &seed)]);
+
+    builder
+        .seed(Some(seed))
+        .build()?
+        .generate_subtitle_file(&mut subtitles)?;
+
+    let (subtitles_path, _temp_file) = super::write_subtitles(
+        observer,
+        &subtitles,
+        opts.subtitles_out.as_ref().map(|s| s.as_ref()),
+    )?;
+
+    let duration = ffmpeg.duration(observer, &opts.video)?;
+
+    let mut command = ffmpeg.progress_command();
+    command.args(&["-y", "-i"]).arg(&opts.video);
+
+    if opts.hardsub {
+        let filter = format!("subtitles='{}'", subtitles_path);
+        command
+            .args(&["-map", "0:v:0", "-map", "0:a?", "-vf", &filter])
+            .args(&["-c:v", "libx264", "-c:a", "copy"]);
+    } else {
+        // Keep everything but the original subtitles, including attachments
+        // (e.g., fonts used by the subtitles)
+        command
+            .arg("-i")
+            .arg(&subtitles_path)
+            .args(&["-map", "0", "-map", "-0:s", "-map", "1"])
+            .args(&["-c", "copy", "-disposition:s:0", "default"]);
+    }
+    command.arg(&opts.output);
+
+    observer.info(
+        "Rendering video",
+        &[("path", &opts.output), ("hardsub", &opts.hardsub)],
+    );
+    ffmpeg.run_with_progress(observer, &mut command, duration)?;
+    observer.info("Rendered video", &[("path", &opts.output)]);
+
+    Ok(())
+}
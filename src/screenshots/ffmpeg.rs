@@ -1,198 +1,551 @@
+use super::render::{ClipOptions, FrameRenderer, ImageOptions};
 use crate::error::*;
-use rand::seq::SliceRandom;
-use rand::Rng;
-use serde_derive::Serialize;
-use slog::Logger;
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::Command;
+use crate::observer::Observer;
+use crate::opts::{ClipFormat, ImageFormat};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
 use subparse::timetypes::TimePoint;
-use subparse::{GenericSubtitleFile, SubtitleEntry, SubtitleFile, SubtitleFormat};
-
-pub fn get_subtitles_from_video(log: &Logger, path: &str) -> Result<(Vec<u8>, SubtitleFormat)> {
-    let output = Command::new("ffmpeg")
-        .args(&["-i", path, "-map", "0:s:0", "-f", "ass", "-"])
-        .output()
-        .context(|| "ffmpeg command failed")?;
-
-    if !output.status.success() {
-        slog::error!(
-            log, "Failed to extract subtitles with ffmpeg";
-            "stderr" => %String::from_utf8_lossy(&output.stderr),
-            "stdout" => %String::from_utf8_lossy(&output.stdout)
-        );
-        return Err(Error::context("ffmpeg command failed"));
-    }
+use subparse::SubtitleFormat;
 
-    Ok((output.stdout, SubtitleFormat::SubStationAlpha))
+/// Renders frames with the ffmpeg command line tools
+pub struct Ffmpeg {
+    /// Path to the `ffmpeg` binary
+    pub ffmpeg: String,
+    /// Path to the `ffprobe` binary
+    pub ffprobe: String,
+    pub image: ImageOptions,
+    pub clip: ClipOptions,
 }
 
-#[derive(Serialize)]
-struct ScreenshotData<'a> {
-    source: &'a str,
-    timestamp_ms: i64,
-    path: &'a str,
-    text: &'a str,
-}
+/// Minimum difference between frames (from 0 to 1) to count as a scene change
+const SCENE_CHANGE_THRESHOLD: f64 = 0.3;
+
+/// Bitrate of the audio in clips, in bits per second
+const CLIP_AUDIO_BITRATE: u64 = 128_000;
+
+impl Ffmpeg {
+    /// Uses the default image and clip options
+    pub fn new(ffmpeg: String, ffprobe: String) -> Self {
+        Ffmpeg {
+            ffmpeg,
+            ffprobe,
+            image: ImageOptions {
+                format: ImageFormat::Jpg,
+                quality: None,
+                scale: None,
+                max_width: None,
+            },
+            clip: ClipOptions {
+                format: ClipFormat::Mp4,
+                audio: false,
+                fps: None,
+                max_size: None,
+            },
+        }
+    }
+
+    /// Returns the duration of `video` in seconds
+    pub fn duration(&self, observer: &dyn Observer, video: &str) -> Result<f64> {
+        let output = self.run(
+            observer,
+            Command::new(&self.ffprobe)
+                .args(&["-v", "error", "-show_entries", "format=duration"])
+                .args(&["-of", "csv=p=0"])
+                .arg(video),
+        )?;
+
+        String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| Error::new(ErrorKind::Ffmpeg, "failed to get video duration"))
+    }
+
+    /// ffmpeg command that writes its progress to stdout, to be run with
+    /// `run_with_progress`
+    pub fn progress_command(&self) -> Command {
+        let mut command = Command::new(&self.ffmpeg);
+        command.args(&["-nostats", "-progress", "pipe:1"]);
+        command
+    }
+
+    /// Runs a command from `progress_command`, reporting its progress through
+    /// `observer` every 5% of `duration` (the length of the output, in seconds)
+    pub fn run_with_progress(
+        &self,
+        observer: &dyn Observer,
+        command: &mut Command,
+        duration: f64,
+    ) -> Result<()> {
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context_kind(ErrorKind::Ffmpeg, || "failed to run ffmpeg")?;
+
+        // Read stderr on another thread, so ffmpeg doesn't block on a full pipe
+        let mut stderr = child.stderr.take().expect("piped stderr");
+        let stderr = std::thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = stderr.read_to_end(&mut output);
+            output
+        });
+
+        let stdout = child.stdout.take().expect("piped stdout");
+        let mut reported = 0;
+        for line in BufReader::new(stdout).lines() {
+            let line = line.context_kind(ErrorKind::Ffmpeg, || "failed to read ffmpeg output")?;
+
+            // Despite the name, `out_time_ms` is in microseconds
+            if line.starts_with("out_time_ms=") {
+                let time = line["out_time_ms=".len()..].parse::<f64>().unwrap_or(0.0) / 1e6;
+                let percent = (time / duration * 100.0).min(100.0).max(0.0) as u32;
+                if percent >= reported + 5 {
+                    reported = percent - percent % 5;
+                    observer.info("Rendering", &[("progress", &format!("{}%", reported))]);
+                }
+            }
+        }
+
+        let status = child
+            .wait()
+            .context_kind(ErrorKind::Ffmpeg, || "failed to run ffmpeg")?;
+        let stderr = stderr.join().unwrap_or_default();
+
+        if !status.success() {
+            observer.error(
+                "ffmpeg command failed",
+                &[("stderr", &String::from_utf8_lossy(&stderr))],
+            );
+            return Err(Error::new(ErrorKind::Ffmpeg, "ffmpeg command failed"));
+        }
+
+        Ok(())
+    }
+
+    /// Filter chain that burns in the subtitles, then resizes the frame
+    fn video_filter(&self, subtitles: &str) -> String {
+        let mut filter = format!("subtitles='{}'", subtitles);
+        filter.push_str(&self.scale_filter(-1));
+        filter
+    }
+
+    /// `keep_aspect` replaces -1 in `--scale`, e.g. -2 keeps the aspect ratio
+    /// while rounding to an even number of pixels
+    fn scale_filter(&self, keep_aspect: i32) -> String {
+        let side = |n: i32| if n == -1 { keep_aspect } else { n };
+
+        if let Some((width, height)) = self.image.scale {
+            format!(",scale={}:{}", side(width), side(height))
+        } else if let Some(max_width) = self.image.max_width {
+            format!(",scale='min(iw,{})':{}", max_width, keep_aspect)
+        } else {
+            String::new()
+        }
+    }
+
+    /// Filter chain for clips. Timestamps are reset after burning in the
+    /// subtitles, so clips start at 0.
+    fn clip_filter(&self, subtitles: &str) -> String {
+        let mut filter = format!("subtitles='{}',setpts=PTS-STARTPTS", subtitles);
+
+        if let Some(fps) = self.clip.fps {
+            filter.push_str(&format!(",fps={}", fps));
+        }
+
+        // Video codecs need even dimensions
+        match self.clip.format {
+            ClipFormat::Gif => filter.push_str(&self.scale_filter(-1)),
+            _ => filter.push_str(&self.scale_filter(-2)),
+        }
+
+        // GIFs only have 256 colors, so generate a palette from the clip itself
+        if self.clip.format == ClipFormat::Gif {
+            filter.push_str(",split[a][b];[a]palettegen[p];[b][p]paletteuse");
+        }
+
+        filter
+    }
+
+    /// Encoder options for the clip format. If there's a size limit, the video
+    /// bitrate is set so the whole clip fits.
+    fn clip_args(&self, duration_ms: i64) -> Result<Vec<String>> {
+        let audio = self.clip.audio && self.clip.format != ClipFormat::Gif;
+        let mut args = match self.clip.format {
+            ClipFormat::Gif => vec![],
+            ClipFormat::Webm => vec!["-c:v", "libvpx-vp9", "-c:a", "libopus"],
+            ClipFormat::Mp4 => vec![
+                "-c:v",
+                "libx264",
+                "-pix_fmt",
+                "yuv420p",
+                "-c:a",
+                "aac",
+                "-movflags",
+                "+faststart",
+            ],
+        }
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<String>>();
 
-pub fn save_screenshots(
-    log: &Logger,
-    video_path: &str,
-    subtitles: &GenericSubtitleFile,
-    filename_format: &str,
-    output_dir: &PathBuf,
-    subtitles_out: Option<String>,
-    count: Option<usize>,
-    resolution_ms: u32,
-) -> Result<()> {
-    let (mut subtitles_file, subtitles_file_path) = match subtitles_out {
-        Some(path) => {
-            let file = std::fs::File::create(&path).context(|| "failed to create file")?;
-            (Box::new(file) as Box<std::io::Write>, path)
-        }
-        None => {
-            let file =
-                tempfile::NamedTempFile::new().context(|| "failed to create temporary file")?;
-            let path = file.path().to_string_lossy().to_string();
-            (Box::new(file) as Box<std::io::Write>, path)
-        }
-    };
-
-    slog::info!(log, "Writing subtitles to file"; "path" => &subtitles_file_path);
-
-    let subtitles_data = subtitles
-        .to_data()
-        .context(|| "failed to serialize subtitle data")?;
-
-    subtitles_file
-        .write(&subtitles_data)
-        .context(|| "failed to write subtitles to file")?;
-
-    let mut rng = rand::thread_rng();
-    let entries_with_timestamps = {
-        let mut subtitle_entries = subtitles
-            .get_subtitle_entries()
-            .context(|| "failed to get subtitle entries")?;
-
-        let resolution_ms = resolution_ms as i64;
-        if resolution_ms > 0 {
-            // Lines that happen in the same interval will only get
-            // one screenshot (chosen at random)
-            subtitle_entries.sort_unstable_by_key(|e| {
-                (
-                    (e.timespan.start.msecs() / resolution_ms),
-                    rand::random::<u8>(),
-                )
-            });
-            subtitle_entries.dedup_by_key(|e| e.timespan.start.msecs() / resolution_ms);
-        }
-
-        let entries_with_ts = get_random_timestamps(subtitle_entries).collect::<Vec<_>>();
-
-        // Take a subset of the subtitles
-        if let Some(c) = count {
-            entries_with_ts
-                .choose_multiple(&mut rng, c)
-                .cloned()
-                .collect()
+        if audio {
+            args.extend(vec!["-b:a".into(), CLIP_AUDIO_BITRATE.to_string()]);
         } else {
-            entries_with_ts
-        }
-    };
-
-    for (text, ts) in entries_with_timestamps {
-        let path = {
-            let mut path = output_dir.clone();
-
-            let mut filename = filename_format
-                .replace("%H", &(ts.mins_comp() / 60).to_string())
-                .replace("%M", &format!("{:02}", ts.mins_comp() % 60))
-                .replace("%S", &format!("{:02}", ts.secs_comp()))
-                .replace("%m", &ts.msecs().to_string())
-                .replace("%f", &format!("{:03}", ts.msecs_comp()));
-
-            let encoded_text = base64::encode_config(&text, base64::URL_SAFE);
-
-            // Max filename length is 255 on most systems. Attempt to fit the Base64-encoded text
-            // in the filename, and if it fails, just encode an empty string.
-            // TODO: This is such a hack
-            filename = if filename.len() + encoded_text.len() > 240 {
-                filename.replace("%t", &base64::encode_config("", base64::URL_SAFE))
-            } else {
-                filename.replace("%t", &encoded_text)
-            };
-
-            path.push(filename);
-            path.set_extension("jpg");
-            path
+            args.push("-an".into());
+        }
+
+        // GIF bitrates can't be set, so their size is only checked after rendering
+        let max_size = match self.clip.format {
+            ClipFormat::Gif => None,
+            _ => self.clip.max_size,
+        };
+        if let Some(max_size) = max_size {
+            let duration_secs = (duration_ms.max(1) as f64) / 1000.0;
+            // Leave some room for the container
+            let mut bitrate = (max_size * 8) as f64 * 0.95 / duration_secs;
+            if audio {
+                bitrate -= CLIP_AUDIO_BITRATE as f64;
+            }
+            if bitrate < 10_000.0 {
+                return Err(Error::new(
+                    ErrorKind::Constraint,
+                    "clip is too long to fit in --max-size",
+                ));
+            }
+            args.extend(vec!["-b:v".into(), (bitrate as u64).to_string()]);
+        }
+
+        Ok(args)
+    }
+
+    /// Encoder options for the image format and quality
+    fn image_args(&self) -> Vec<String> {
+        let quality = match self.image.quality {
+            Some(quality) => quality,
+            None => return Vec::new(),
         };
 
-        let output_path = path.to_string_lossy();
-        let subtitles_arg = format!("subtitles='{}'", subtitles_file_path);
-
-        slog::info!(log, "Saving screenshot"; "text" => &text, "path" => %output_path);
-
-        let output = Command::new("ffmpeg")
-            .args(&[
-                "-y",
-                "-ss",
-                &format!("{}", ts.secs_f64()),
-                "-copyts",
-                "-i",
-                video_path,
-                "-map",
-                "0:v",
-                "-vf",
-                &subtitles_arg,
-                "-vframes",
-                "1",
-                output_path.as_ref(),
-            ])
+        match self.image.format {
+            // The JPEG encoder's scale goes from 2 (best) to 31 (worst)
+            ImageFormat::Jpg => vec!["-q:v".into(), (2 + (100 - quality) * 29 / 99).to_string()],
+            ImageFormat::Webp => vec!["-quality".into(), quality.to_string()],
+            ImageFormat::Png => Vec::new(),
+        }
+    }
+
+    fn run(&self, observer: &dyn Observer, command: &mut Command) -> Result<Output> {
+        let output = command
             .output()
-            .context(|| "failed to run ffmpeg")?;
+            .context_kind(ErrorKind::Ffmpeg, || "failed to run ffmpeg")?;
 
         if !output.status.success() {
-            slog::error!(
-                log, "Failed to generate screenshots with ffmpeg";
-                "stderr" => %String::from_utf8_lossy(&output.stderr),
-                "stdout" => %String::from_utf8_lossy(&output.stdout)
+            observer.error(
+                "ffmpeg command failed",
+                &[
+                    ("stderr", &String::from_utf8_lossy(&output.stderr)),
+                    ("stdout", &String::from_utf8_lossy(&output.stdout)),
+                ],
             );
-            return Err(Error::context("ffmpeg command failed"));
+            return Err(Error::new(ErrorKind::Ffmpeg, "ffmpeg command failed"));
         }
 
-        // TODO: This is incredibly hacky and should not be printed here
-        println!(
-            "{}",
-            serde_json::to_string(&ScreenshotData {
-                source: video_path,
-                timestamp_ms: ts.msecs(),
-                path: output_path.as_ref(),
-                text: &text
+        Ok(output)
+    }
+}
+
+impl FrameRenderer for Ffmpeg {
+    fn extract_subtitles(
+        &self,
+        observer: &dyn Observer,
+        video: &str,
+    ) -> Result<(Vec<u8>, SubtitleFormat)> {
+        let streams = self.run(
+            observer,
+            Command::new(&self.ffprobe)
+                .args(&["-v", "error", "-select_streams", "s"])
+                .args(&["-show_entries", "stream=index", "-of", "csv=p=0"])
+                .arg(video),
+        )?;
+        if streams.stdout.iter().all(u8::is_ascii_whitespace) {
+            return Err(Error::new(
+                ErrorKind::NoInput,
+                "video has no subtitle streams (use --subtitles-ref instead)",
+            ));
+        }
+
+        let output = self.run(
+            observer,
+            Command::new(&self.ffmpeg)
+                .arg("-i")
+                .arg(video)
+                .args(&["-map", "0:s:0", "-f", "ass", "-"]),
+        )?;
+
+        Ok((output.stdout, SubtitleFormat::SubStationAlpha))
+    }
+
+    /// Decodes the whole video, and reads the timestamp of each frame selected
+    /// by the scene detection filter from the output of `showinfo`
+    fn scene_changes(&self, observer: &dyn Observer, video: &str) -> Result<Vec<TimePoint>> {
+        let filter = format!("select='gt(scene,{})',showinfo", SCENE_CHANGE_THRESHOLD);
+        let output = self.run(
+            observer,
+            Command::new(&self.ffmpeg)
+                .args(&["-nostats", "-i"])
+                .arg(video)
+                .args(&["-map", "0:v:0", "-vf", &filter, "-f", "null", "-"]),
+        )?;
+
+        let changes = String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .filter_map(|line| {
+                let time = &line[line.find("pts_time:")? + "pts_time:".len()..];
+                let time = time.split_whitespace().next()?.parse::<f64>().ok()?;
+                Some(TimePoint::from_msecs((time * 1000.0) as i64))
             })
-            .context(|| "failed to serialize output")?
-        );
+            .collect();
+
+        Ok(changes)
     }
 
-    Ok(())
-}
+    fn render_frame(
+        &self,
+        observer: &dyn Observer,
+        video: &str,
+        subtitles: &str,
+        timestamp: TimePoint,
+        output: &Path,
+    ) -> Result<()> {
+        self.run(
+            observer,
+            Command::new(&self.ffmpeg)
+                .args(&["-y", "-ss", &format!("{}", timestamp.secs_f64())])
+                .args(&["-copyts", "-i"])
+                .arg(video)
+                .args(&["-map", "0:v", "-vf", &self.video_filter(subtitles)])
+                .args(&["-vframes", "1"])
+                .args(&self.image_args())
+                .arg(output),
+        )?;
+
+        Ok(())
+    }
+
+    fn render_clip(
+        &self,
+        observer: &dyn Observer,
+        video: &str,
+        subtitles: &str,
+        start: TimePoint,
+        end: TimePoint,
+        output: &Path,
+    ) -> Result<()> {
+        let duration_ms = end.msecs() - start.msecs();
+        let mut command = Command::new(&self.ffmpeg);
+        command
+            .args(&["-y", "-ss", &format!("{}", start.secs_f64())])
+            .args(&["-copyts", "-i"])
+            .arg(video)
+            .args(&["-map", "0:v:0"]);
+        if self.clip.audio && self.clip.format != ClipFormat::Gif {
+            command.args(&["-map", "0:a:0?", "-af", "asetpts=PTS-STARTPTS"]);
+        }
+        command
+            .args(&["-t", &format!("{}", duration_ms as f64 / 1000.0)])
+            .args(&["-vf", &self.clip_filter(subtitles)])
+            .args(&self.clip_args(duration_ms)?)
+            .arg(output);
 
-pub fn get_random_timestamps(
-    subs: impl IntoIterator<Item = SubtitleEntry>,
-) -> impl Iterator<Item = (String, TimePoint)> {
-    let mut rng = rand::thread_rng();
-
-    subs.into_iter().filter_map(move |entry| match entry.line {
-        None => None,
-        Some(ref line) if line.trim().is_empty() => None,
-        Some(line) => {
-            let mut start = entry.timespan.start.msecs();
-            let mut end = entry.timespan.end.msecs();
-            if start > end {
-                std::mem::swap(&mut start, &mut end);
+        self.run(observer, &mut command)?;
+
+        if let Some(max_size) = self.clip.max_size {
+            let size = std::fs::metadata(output)
+                .context(|| "failed to read clip size")?
+                .len();
+            if size > max_size {
+                return Err(Error::new(
+                    ErrorKind::Constraint,
+                    format!("clip is larger than --max-size ({} bytes)", size),
+                ));
             }
-            let timepoint = TimePoint::from_msecs(rng.gen_range(start, end));
+        }
+
+        Ok(())
+    }
+
+    /// Renders every frame with a single ffmpeg process, which decodes the
+    /// video once instead of seeking and loading the subtitles for each frame
+    fn render_frames(
+        &self,
+        observer: &dyn Observer,
+        video: &str,
+        subtitles: &str,
+        frames: &[(TimePoint, PathBuf)],
+    ) -> Result<()> {
+        let mut timestamps = frames
+            .iter()
+            .map(|(ts, _)| ts.msecs())
+            .collect::<Vec<i64>>();
+        // ffmpeg saves frames in playback order, whatever order they're given in
+        timestamps.sort();
+        timestamps.dedup();
+
+        if timestamps.is_empty() {
+            return Ok(());
+        }
+
+        // Selects the first frame at or after each timestamp
+        let select = timestamps
+            .iter()
+            .map(|ms| {
+                let secs = *ms as f64 / 1000.0;
+                format!("gte(t,{0})*(isnan(prev_t)+lt(prev_t,{0}))", secs)
+            })
+            .collect::<Vec<String>>()
+            .join("+");
+        let filter = format!("{},select='{}'", self.video_filter(subtitles), select);
+
+        // Frames are numbered in the order they're saved, and copied to their
+        // final paths once they've all been saved
+        let output_dir = frames[0].1.parent().unwrap_or_else(|| Path::new("."));
+        let frames_dir =
+            tempfile::tempdir_in(output_dir).context(|| "failed to create temporary directory")?;
+        let extension = self.image.format.extension();
+        let frame_path = |i: usize| {
+            frames_dir
+                .path()
+                .join(format!("{:06}.{}", i + 1, extension))
+        };
+
+        self.run(
+            observer,
+            Command::new(&self.ffmpeg)
+                .args(&["-y", "-i"])
+                .arg(video)
+                .args(&["-map", "0:v", "-vf", &filter, "-vsync", "0"])
+                .args(&self.image_args())
+                .arg(frames_dir.path().join(format!("%06d.{}", extension))),
+        )?;
+
+        // Timestamps closer together than a frame select the same frame, so the
+        // frames can't be matched to timestamps
+        if !frame_path(timestamps.len() - 1).exists() || frame_path(timestamps.len()).exists() {
+            return Err(Error::new(
+                ErrorKind::Ffmpeg,
+                "ffmpeg saved a different number of frames than requested",
+            ));
+        }
 
-            Some((line, timepoint))
+        for (ts, output) in frames {
+            let i = timestamps
+                .binary_search(&ts.msecs())
+                .expect("timestamp of frame");
+            std::fs::copy(frame_path(i), output).context(|| "failed to save frame")?;
         }
-    })
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a script that stands in for ffmpeg, saving one numbered frame per
+    /// timestamp in the `select` filter to the path pattern in its last argument
+    #[cfg(unix)]
+    fn fake_ffmpeg(dir: &Path) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join("ffmpeg");
+        let script = r#"#!/bin/sh
+for last; do :; done
+frames=$(printf '%s\n' "$@" | grep -o 'gte(' | wc -l)
+i=1
+while [ "$i" -le "$frames" ]; do
+    echo "frame $i" > "$(printf "$last" "$i")"
+    i=$((i + 1))
+done
+"#;
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn with_image(format: ImageFormat, quality: Option<u32>) -> Ffmpeg {
+        Ffmpeg {
+            image: ImageOptions {
+                format,
+                quality,
+                scale: None,
+                max_width: None,
+            },
+            ..Ffmpeg::new("ffmpeg".to_owned(), "ffprobe".to_owned())
+        }
+    }
+
+    #[test]
+    fn jpg_quality_maps_to_qscale() {
+        let qscale = |quality| {
+            let args = with_image(ImageFormat::Jpg, Some(quality)).image_args();
+            assert_eq!(args[0], "-q:v");
+            args[1].parse::<u32>().unwrap()
+        };
+
+        assert_eq!(qscale(100), 2);
+        assert_eq!(qscale(50), 16);
+        assert_eq!(qscale(1), 31);
+
+        // Higher quality never gives a worse scale
+        for quality in 2..=100 {
+            assert!(qscale(quality) <= qscale(quality - 1));
+        }
+    }
+
+    #[test]
+    fn quality_args_depend_on_format() {
+        assert_eq!(
+            with_image(ImageFormat::Webp, Some(80)).image_args(),
+            vec!["-quality", "80"]
+        );
+        assert!(with_image(ImageFormat::Png, Some(80))
+            .image_args()
+            .is_empty());
+        assert!(with_image(ImageFormat::Jpg, None).image_args().is_empty());
+    }
+
+    #[test]
+    fn scale_filter_keeps_aspect_ratio() {
+        let mut ffmpeg = with_image(ImageFormat::Jpg, None);
+        assert_eq!(ffmpeg.scale_filter(-1), "");
+
+        ffmpeg.image.scale = Some((640, -1));
+        assert_eq!(ffmpeg.scale_filter(-1), ",scale=640:-1");
+        assert_eq!(ffmpeg.scale_filter(-2), ",scale=640:-2");
+
+        ffmpeg.image.scale = None;
+        ffmpeg.image.max_width = Some(1280);
+        assert_eq!(ffmpeg.scale_filter(-2), ",scale='min(iw,1280)':-2");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn render_frames_accepts_unsorted_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let ffmpeg = Ffmpeg::new(fake_ffmpeg(dir.path()), "ffprobe".to_owned());
+
+        let frames = [(3000, "a.jpg"), (1000, "b.jpg"), (3000, "c.jpg")]
+            .iter()
+            .map(|&(ms, name)| (TimePoint::from_msecs(ms), dir.path().join(name)))
+            .collect::<Vec<_>>();
+        ffmpeg
+            .render_frames(&crate::NoopObserver, "video.mkv", "subs.ass", &frames)
+            .unwrap();
+
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("a.jpg"), "frame 2\n");
+        assert_eq!(read("b.jpg"), "frame 1\n");
+        assert_eq!(read("c.jpg"), "frame 2\n");
+    }
 }
@@ -0,0 +1,206 @@
+use crate::error::*;
+use crate::score::Score;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Options a screenshot run was started with
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunInfo {
+    pub model: String,
+    pub seed: u64,
+    pub video: String,
+    pub subtitles_ref: Option<String>,
+    pub subtitles_out: Option<String>,
+    pub options: RunOptions,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunOptions {
+    pub min_length: Option<usize>,
+    pub min_support: usize,
+    pub temperature: f64,
+    pub candidates: usize,
+    pub blocklist: Option<String>,
+    pub count: Option<usize>,
+    pub all: bool,
+    pub resolution_ms: u32,
+    pub format: String,
+    #[serde(default)]
+    pub timestamp_strategy: String,
+    #[serde(default)]
+    pub image_format: String,
+    #[serde(default)]
+    pub quality: Option<u32>,
+    /// Width and height
+    #[serde(default)]
+    pub scale: Option<(i32, i32)>,
+    #[serde(default)]
+    pub max_width: Option<u32>,
+    #[serde(default)]
+    pub clip: bool,
+    #[serde(default)]
+    pub clip_format: String,
+    #[serde(default)]
+    pub padding_ms: u32,
+    #[serde(default)]
+    pub audio: bool,
+    #[serde(default)]
+    pub fps: Option<u32>,
+    /// In bytes
+    #[serde(default)]
+    pub max_size: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScreenshotRecord {
+    /// Time of the frame, or the start of the clip
+    pub timestamp_ms: i64,
+    /// End of the clip, if saving clips
+    #[serde(default)]
+    pub end_ms: Option<i64>,
+    pub path: String,
+    pub text: String,
+    /// Text of the line in the reference subtitles that was replaced
+    pub original: String,
+    /// Only available for SSA/ASS subtitles
+    pub style: Option<String>,
+    /// Only available for SSA/ASS subtitles
+    pub actor: Option<String>,
+    pub score: Option<Score>,
+}
+
+/// Describes a screenshot run, and every screenshot saved so far
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(flatten)]
+    pub run: RunInfo,
+    pub screenshots: Vec<ScreenshotRecord>,
+    /// Score of each generated line, by text, so screenshots saved by a
+    /// resumed run are scored too
+    #[serde(default)]
+    pub scores: BTreeMap<String, Score>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest> {
+        let file = std::fs::File::open(path).context(|| "failed to open manifest")?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .context_kind(ErrorKind::Parse, || "failed to parse manifest")
+    }
+
+    /// Adds a record, keeping records sorted by timestamp and then path, so the
+    /// order doesn't depend on which screenshot was saved first. Replaces the
+    /// record of a screenshot that was saved to the same path.
+    pub fn add(&mut self, record: ScreenshotRecord) {
+        self.screenshots.retain(|other| other.path != record.path);
+        let index = self
+            .screenshots
+            .iter()
+            .position(|other| {
+                (other.timestamp_ms, &other.path) > (record.timestamp_ms, &record.path)
+            })
+            .unwrap_or_else(|| self.screenshots.len());
+        self.screenshots.insert(index, record);
+    }
+
+    /// Writes to a temporary file in the same directory first, then renames it,
+    /// so the file at `path` is always a complete manifest
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let mut file = tempfile::NamedTempFile::new_in(dir)
+            .context(|| "failed to create temporary manifest file")?;
+        serde_json::to_writer_pretty(&mut file, self)
+            .context_kind(ErrorKind::Io, || "failed to write manifest")?;
+        file.persist(path)
+            .context_kind(ErrorKind::Io, || "failed to save manifest")?;
+
+        Ok(())
+    }
+}
+
+/// Names of the options that differ between two runs, e.g., to check that a
+/// resumed run uses the same options as the original one
+pub fn changed_options(original: &RunInfo, current: &RunInfo) -> Vec<String> {
+    let fields = |run: &RunInfo| {
+        let mut fields = match serde_json::to_value(run).expect("serializable run info") {
+            serde_json::Value::Object(fields) => fields,
+            _ => serde_json::Map::new(),
+        };
+        if let Some(serde_json::Value::Object(options)) = fields.remove("options") {
+            fields.extend(options);
+        }
+        fields
+    };
+
+    let current = fields(current);
+    fields(original)
+        .into_iter()
+        .filter(|(name, value)| current.get(name) != Some(value))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Style and actor of a dialogue line in an SSA/ASS file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DialogueInfo {
+    pub style: Option<String>,
+    pub actor: Option<String>,
+}
+
+/// Returns the style and actor of each `Dialogue` line in the `[Events]`
+/// section of an SSA/ASS file, in the same order as the file's subtitle entries
+pub fn dialogue_info(data: &str) -> Vec<DialogueInfo> {
+    let mut fields = vec![
+        "Layer", "Start", "End", "Style", "Name", "MarginL", "MarginR", "MarginV", "Effect", "Text",
+    ]
+    .into_iter()
+    .map(str::to_owned)
+    .collect::<Vec<String>>();
+    let mut in_events = false;
+    let mut info = Vec::new();
+
+    for line in data.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_events = line.eq_ignore_ascii_case("[events]");
+            continue;
+        }
+
+        if !in_events {
+            continue;
+        }
+
+        if line.starts_with("Format:") {
+            fields = line["Format:".len()..]
+                .split(',')
+                .map(|field| field.trim().to_owned())
+                .collect();
+        } else if line.starts_with("Dialogue:") {
+            // The text is the last field, and can contain commas
+            let values = line["Dialogue:".len()..]
+                .splitn(fields.len(), ',')
+                .map(str::trim)
+                .collect::<Vec<&str>>();
+
+            let get = |name: &str| {
+                fields
+                    .iter()
+                    .position(|field| field == name)
+                    .and_then(|i| values.get(i))
+                    .filter(|value| !value.is_empty())
+                    .map(|value| value.to_string())
+            };
+
+            info.push(DialogueInfo {
+                style: get("Style"),
+                actor: get("Name").or_else(|| get("Actor")),
+            });
+        }
+    }
+
+    info
+}
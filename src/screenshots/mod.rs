@@ -1,41 +1,713 @@
+mod episode;
 mod ffmpeg;
+mod manifest;
+mod render;
+mod timestamps;
 
+pub use self::episode::render_episode;
+
+use self::ffmpeg::Ffmpeg;
+use self::manifest::{Manifest, RunInfo, RunOptions, ScreenshotRecord};
+use self::render::{ClipOptions, FakeRenderer, FrameRenderer, ImageOptions};
 use crate::error::*;
-use crate::opts::Screenshots;
-use slog::Logger;
+use crate::observer::Observer;
+use crate::opts::{Renderer, Screenshots};
+use crate::score::Score;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use subparse::timetypes::{TimePoint, TimeSpan};
+use subparse::{GenericSubtitleFile, SubtitleFile, SubtitleFormat};
+
+/// Generated subtitle line, along with the reference line it replaced
+#[derive(Clone, Debug)]
+pub struct SubtitleLine {
+    pub timespan: TimeSpan,
+    pub text: String,
+    pub original: String,
+    pub style: Option<String>,
+    pub actor: Option<String>,
+}
+
+pub fn generate_screenshots(observer: &dyn Observer, opts: Screenshots) -> Result<()> {
+    let renderer: Box<dyn FrameRenderer> = match opts.renderer {
+        Renderer::Ffmpeg => Box::new(Ffmpeg {
+            image: ImageOptions {
+                format: opts.image_format,
+                quality: opts.quality,
+                scale: opts.scale,
+                max_width: opts.max_width,
+            },
+            clip: ClipOptions {
+                format: opts.clip_format(),
+                audio: opts.audio,
+                fps: opts.fps,
+                max_size: opts.max_size,
+            },
+            ..Ffmpeg::new(opts.ffmpeg_path.clone(), opts.ffprobe_path.clone())
+        }),
+        Renderer::Fake => Box::new(FakeRenderer),
+    };
+
+    let (bytes, format) = read_reference_subtitles(
+        observer,
+        renderer.as_ref(),
+        opts.subtitles_ref.as_ref().map(|s| s.as_ref()),
+        &opts.video,
+    )?;
 
-pub fn generate_screenshots(log: &Logger, opts: Screenshots) -> Result<()> {
-    // Get subtitles from specific subtitles file, or attempt to extract from video
-    let (bytes, format) = match opts.subtitles_ref {
+    let mut subtitles = crate::parse_subtitles(&mut bytes.as_slice(), format, true)?;
+    let originals = subtitles
+        .get_subtitle_entries()
+        .context_kind(ErrorKind::Parse, || "failed to get subtitle entries")?;
+
+    // Style and actor can only be read from SSA/ASS files, and only if every
+    // dialogue line was matched to an entry
+    let mut dialogue = match format {
+        SubtitleFormat::SubStationAlpha => {
+            manifest::dialogue_info(&String::from_utf8_lossy(&bytes))
+        }
+        _ => Vec::new(),
+    };
+    if dialogue.len() != originals.len() {
+        dialogue.clear();
+    }
+
+    let manifest_path = opts
+        .manifest
+        .clone()
+        .unwrap_or_else(|| opts.output_dir.join("manifest.json"));
+
+    let mut manifest = if opts.resume {
+        observer.info(
+            "Resuming from manifest",
+            &[("path", &manifest_path.display())],
+        );
+        let manifest = Manifest::load(&manifest_path)?;
+
+        // The subtitles and timestamps were picked with the original options,
+        // so different options would describe a different run
+        let current = run_info(&opts, opts.seed.unwrap_or(manifest.run.seed));
+        let changed = manifest::changed_options(&manifest.run, &current);
+        if !changed.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Config,
+                format!(
+                    "--resume needs the same options as the original run, but these \
+                     are different: {}",
+                    changed.join(", ")
+                ),
+            ));
+        }
+
+        // `--subtitles-out` is required by `--resume`
+        let path = opts.subtitles_out.as_ref().expect("subtitles out path");
+        observer.info("Reading generated subtitles file", &[("path", &path)]);
+        let format = subparse::get_subtitle_format_by_ending_err(path)
+            .context_kind(ErrorKind::Parse, || "failed to determine subtitle format")?;
+        let bytes = std::fs::read(path).context(|| "failed to read generated subtitles file")?;
+        subtitles = crate::parse_subtitles(&mut bytes.as_slice(), format, true)?;
+
+        manifest
+    } else {
+        let seed = opts.seed.unwrap_or_else(rand::random);
+        let scores = generate_subtitles(observer, &opts, seed, &mut subtitles)?;
+        Manifest {
+            run: run_info(&opts, seed),
+            screenshots: Vec::new(),
+            scores: scores.into_iter().collect(),
+        }
+    };
+
+    // Generation replaces the text of each entry in place, so entries are in
+    // the same order as the originals
+    let entries = subtitles
+        .get_subtitle_entries()
+        .context_kind(ErrorKind::Parse, || "failed to get subtitle entries")?;
+    if entries.len() != originals.len() {
+        return Err(Error::new(
+            ErrorKind::Config,
+            "generated subtitles don't match the reference subtitles",
+        ));
+    }
+
+    let lines = entries
+        .into_iter()
+        .zip(originals)
+        .enumerate()
+        .map(|(i, (entry, original))| {
+            let info = dialogue.get(i).cloned().unwrap_or_default();
+            SubtitleLine {
+                timespan: entry.timespan,
+                text: entry.line.unwrap_or_default(),
+                original: original.line.unwrap_or_default(),
+                style: info.style,
+                actor: info.actor,
+            }
+        })
+        .collect::<Vec<SubtitleLine>>();
+
+    // Timestamps are picked with the same seed, so a run can be reproduced exactly,
+    // so resuming picks the same timestamps as the original run
+    let mut rng = StdRng::seed_from_u64(manifest.run.seed);
+
+    let (subtitles_path, _temp_file) = if opts.resume {
+        (
+            opts.subtitles_out.clone().expect("subtitles out path"),
+            None,
+        )
+    } else {
+        write_subtitles(
+            observer,
+            &subtitles,
+            opts.subtitles_out.as_ref().map(|s| s.as_ref()),
+        )?
+    };
+
+    let output = Output {
+        subtitles_path: &subtitles_path,
+        manifest: &mut manifest,
+        manifest_path: &manifest_path,
+    };
+    save_screenshots(observer, renderer.as_ref(), &opts, lines, &mut rng, output)?;
+
+    observer.info(
+        "Saved manifest",
+        &[
+            ("path", &manifest_path.display()),
+            ("screenshots", &manifest.screenshots.len()),
+        ],
+    );
+
+    Ok(())
+}
+
+/// Options that describe the run, to be saved in the manifest
+fn run_info(opts: &Screenshots, seed: u64) -> RunInfo {
+    RunInfo {
+        model: opts.model.clone(),
+        seed,
+        video: opts.video.clone(),
+        subtitles_ref: opts.subtitles_ref.clone(),
+        subtitles_out: opts.subtitles_out.clone(),
+        options: RunOptions {
+            min_length: opts.generation.min_length,
+            min_support: opts.generation.min_support,
+            temperature: opts.generation.temperature,
+            candidates: opts.generation.candidates,
+            blocklist: opts.generation.blocklist.clone(),
+            count: opts.count,
+            all: opts.save_all,
+            resolution_ms: opts.resolution_ms,
+            format: opts.format.clone(),
+            timestamp_strategy: opts.timestamp_strategy.to_string(),
+            image_format: opts.image_format.extension().to_owned(),
+            clip: opts.clip,
+            clip_format: opts.clip_format().extension().to_owned(),
+            padding_ms: opts.padding_ms(),
+            audio: opts.audio,
+            fps: opts.fps,
+            max_size: opts.max_size,
+            quality: opts.quality,
+            scale: opts.scale,
+            max_width: opts.max_width,
+        },
+    }
+}
+
+/// Reads subtitles from a specific subtitles file, or attempts to extract them
+/// from the video
+fn read_reference_subtitles(
+    observer: &dyn Observer,
+    renderer: &dyn FrameRenderer,
+    subtitles_ref: Option<&str>,
+    video: &str,
+) -> Result<(Vec<u8>, SubtitleFormat)> {
+    match subtitles_ref {
+        Some(path) => {
+            observer.info("Reading subtitles file", &[("path", &path)]);
+            let format = subparse::get_subtitle_format_by_ending_err(path)
+                .context_kind(ErrorKind::Parse, || "failed to determine subtitle format")?;
+            let bytes = std::fs::read(path).context(|| "failed to read input subtitles file")?;
+            Ok((bytes, format))
+        }
+        None => {
+            observer.info(
+                "Attempting to extract subtitles from video",
+                &[("path", &video)],
+            );
+            renderer.extract_subtitles(observer, video)
+        }
+    }
+}
+
+/// Loads the model and filter, and replaces the text of each line in `subtitles`
+fn generate_subtitles(
+    observer: &dyn Observer,
+    opts: &Screenshots,
+    seed: u64,
+    subtitles: &mut GenericSubtitleFile,
+) -> Result<HashMap<String, Score>> {
+    let builder = crate::generate::builder_from_opts(observer, &opts.model, &opts.generation)?;
+
+    observer.info("Generating subtitles", &[("seed", &seed)]);
+
+    builder
+        .seed(Some(seed))
+        .build()?
+        .generate_subtitle_file(subtitles)
+}
+
+/// Writes the generated subtitles to `path`, or to a temporary file if
+/// unspecified. Returns the path written to, and the temporary file, which is
+/// removed when dropped.
+fn write_subtitles(
+    observer: &dyn Observer,
+    subtitles: &GenericSubtitleFile,
+    path: Option<&str>,
+) -> Result<(String, Option<tempfile::NamedTempFile>)> {
+    let (mut subtitles_file, subtitles_file_path, temp_file) = match path {
         Some(path) => {
-            slog::info!(log, "Reading subtitles file"; "path" => &path);
-            let format = subparse::get_subtitle_format_by_ending_err(&path)
-                .context(|| "failed to determine subtitle format")?;
-            let bytes = std::fs::read(&path).context(|| "failed to read input subtitles file")?;
-            (bytes, format)
+            let file = std::fs::File::create(path).context(|| "failed to create file")?;
+            (file, path.to_owned(), None)
         }
         None => {
-            let path = &opts.video;
-            slog::info!(log, "Attempting to extract subtitles from video"; "path" => path);
-            ffmpeg::get_subtitles_from_video(log, path)?
+            let temp_file =
+                tempfile::NamedTempFile::new().context(|| "failed to create temporary file")?;
+            let file = temp_file
+                .reopen()
+                .context(|| "failed to open temporary file")?;
+            let path = temp_file.path().to_string_lossy().to_string();
+            (file, path, Some(temp_file))
         }
     };
 
-    let mut subtitles = crate::parse_subtitles(&mut bytes.as_slice(), format, true)?;
+    observer.info(
+        "Writing subtitles to file",
+        &[("path", &subtitles_file_path)],
+    );
 
-    slog::info!(log, "Loading model from file"; "path" => &opts.model);
-    let model = crate::load_model(&opts.model)?;
+    let subtitles_data = subtitles
+        .to_data()
+        .context_kind(ErrorKind::Parse, || "failed to serialize subtitle data")?;
 
-    crate::generate_subtitle_file(&log, &mut subtitles, model, None, opts.min_length)?;
+    subtitles_file
+        .write_all(&subtitles_data)
+        .context(|| "failed to write subtitles to file")?;
 
-    ffmpeg::save_screenshots(
-        log,
-        &opts.video,
-        &subtitles,
-        &opts.format,
-        &opts.output_dir,
-        opts.subtitles_out,
-        opts.count,
-        opts.resolution_ms,
-    )
+    Ok((subtitles_file_path, temp_file))
+}
+
+/// Files that screenshots are saved with
+struct Output<'a> {
+    /// Generated subtitles to burn into the screenshots
+    subtitles_path: &'a str,
+    manifest: &'a mut Manifest,
+    manifest_path: &'a Path,
+}
+
+/// Saves a screenshot for each selected line, and adds it to the manifest.
+/// The manifest is saved after every screenshot, so it stays valid if a run
+/// is interrupted. When resuming, screenshots that are already in the manifest
+/// are skipped.
+fn save_screenshots(
+    observer: &dyn Observer,
+    renderer: &dyn FrameRenderer,
+    opts: &Screenshots,
+    lines: Vec<SubtitleLine>,
+    rng: &mut impl Rng,
+    output: Output,
+) -> Result<()> {
+    let Output {
+        subtitles_path,
+        manifest,
+        manifest_path,
+    } = output;
+
+    let lines_with_timestamps = {
+        let mut lines = lines
+            .into_iter()
+            .filter(|line| !line.text.trim().is_empty())
+            .collect::<Vec<SubtitleLine>>();
+
+        let resolution_ms = opts.resolution_ms as i64;
+        if resolution_ms > 0 {
+            // Lines that happen in the same interval will only get
+            // one screenshot (chosen at random)
+            lines.shuffle(rng);
+            lines.sort_by_key(|line| line.timespan.start.msecs() / resolution_ms);
+            lines.dedup_by_key(|line| line.timespan.start.msecs() / resolution_ms);
+        }
+
+        let mut lines_with_ts = timestamps::pick_timestamps(observer, renderer, opts, lines, rng)?;
+
+        // Take a subset of the subtitles
+        if let Some(c) = opts.count {
+            lines_with_ts = lines_with_ts.choose_multiple(rng, c).cloned().collect();
+        }
+
+        // Keep the manifest in playback order
+        lines_with_ts.sort_by_key(|(_, ts)| ts.msecs());
+        lines_with_ts
+    };
+
+    manifest.save(manifest_path)?;
+    let scores = &manifest.scores.clone();
+
+    let mut screenshots = Vec::new();
+    for (line, ts) in lines_with_timestamps {
+        let path = screenshot_path(opts, &line.text, ts);
+
+        // Records are only added once a screenshot is completely saved. A file
+        // without one may have been cut off by an interruption, so it's saved again.
+        let output_path = path.to_string_lossy().into_owned();
+        if opts.resume
+            && manifest
+                .screenshots
+                .iter()
+                .any(|record| record.path == output_path)
+        {
+            observer.debug("Skipping saved screenshot", &[("path", &output_path)]);
+            continue;
+        }
+
+        screenshots.push((line, ts, path));
+    }
+
+    // Clips are always saved one at a time
+    if opts.batch && !opts.clip && !screenshots.is_empty() {
+        let frames = screenshots
+            .iter()
+            .map(|(_, ts, path)| (*ts, path.clone()))
+            .collect::<Vec<_>>();
+
+        observer.info(
+            "Saving screenshots in one pass",
+            &[("screenshots", &frames.len())],
+        );
+
+        match renderer.render_frames(observer, &opts.video, subtitles_path, &frames) {
+            Ok(()) => {
+                for (line, ts, path) in screenshots {
+                    let output_path = path.to_string_lossy().into_owned();
+                    manifest.add(record(opts, line, ts, output_path, scores));
+                }
+                return manifest.save(manifest_path);
+            }
+            Err(err) => observer.warn(
+                "Failed to save screenshots in one pass, saving them one at a time",
+                &[("description", &err)],
+            ),
+        }
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.jobs)
+        .build()
+        .context_kind(ErrorKind::Io, || "failed to create thread pool")?;
+
+    let total = screenshots.len();
+    let manifest = Mutex::new(manifest);
+
+    // Errors aren't `Send`, so they're logged here and only their kinds are
+    // returned. Records are inserted in timestamp order, so the manifest is the
+    // same regardless of the order screenshots finish in.
+    let errors = pool.install(|| {
+        screenshots
+            .into_par_iter()
+            .filter_map(|(line, ts, path)| {
+                let output_path = path.to_string_lossy().into_owned();
+                observer.info(
+                    "Saving screenshot",
+                    &[("text", &line.text), ("path", &output_path)],
+                );
+
+                let result = match clip_end(opts, &line) {
+                    Some(end) => {
+                        renderer.render_clip(observer, &opts.video, subtitles_path, ts, end, &path)
+                    }
+                    None => renderer.render_frame(observer, &opts.video, subtitles_path, ts, &path),
+                };
+
+                let result = result.and_then(|()| {
+                    let mut manifest = manifest.lock().expect("manifest lock");
+                    manifest.add(record(opts, line, ts, output_path.clone(), scores));
+                    manifest.save(manifest_path)
+                });
+
+                match result {
+                    Ok(()) => None,
+                    Err(err) => {
+                        // Keep saving the other screenshots
+                        observer.error(
+                            "Failed to save screenshot",
+                            &[("path", &output_path), ("description", &err)],
+                        );
+                        Some(err.kind())
+                    }
+                }
+            })
+            .collect::<Vec<ErrorKind>>()
+    });
+
+    match errors.first() {
+        None => Ok(()),
+        Some(&kind) => Err(Error::new(
+            kind,
+            format!("failed to save {} of {} screenshots", errors.len(), total),
+        )),
+    }
+}
+
+fn screenshot_path(opts: &Screenshots, text: &str, ts: TimePoint) -> PathBuf {
+    let mut path = opts.output_dir.clone();
+
+    let mut filename = opts
+        .format
+        .replace("%H", &(ts.mins_comp() / 60).to_string())
+        .replace("%M", &format!("{:02}", ts.mins_comp() % 60))
+        .replace("%S", &format!("{:02}", ts.secs_comp()))
+        .replace("%m", &ts.msecs().to_string())
+        .replace("%f", &format!("{:03}", ts.msecs_comp()));
+
+    let encoded_text = base64::encode_config(text, base64::URL_SAFE);
+
+    // Max filename length is 255 on most systems. Attempt to fit the Base64-encoded text
+    // in the filename, and if it fails, just encode an empty string.
+    // TODO: This is such a hack
+    filename = if filename.len() + encoded_text.len() > 240 {
+        filename.replace("%t", &base64::encode_config("", base64::URL_SAFE))
+    } else {
+        filename.replace("%t", &encoded_text)
+    };
+
+    path.push(filename);
+    if opts.clip {
+        path.set_extension(opts.clip_format().extension());
+    } else {
+        path.set_extension(opts.image_format.extension());
+    }
+    path
+}
+
+fn record(
+    opts: &Screenshots,
+    line: SubtitleLine,
+    ts: TimePoint,
+    path: String,
+    scores: &BTreeMap<String, Score>,
+) -> ScreenshotRecord {
+    ScreenshotRecord {
+        timestamp_ms: ts.msecs(),
+        end_ms: clip_end(opts, &line).map(|end| end.msecs()),
+        path,
+        score: scores.get(&line.text).cloned(),
+        text: line.text,
+        original: line.original,
+        style: line.style,
+        actor: line.actor,
+    }
+}
+
+/// End of the clip of `line`, if saving clips
+fn clip_end(opts: &Screenshots, line: &SubtitleLine) -> Option<TimePoint> {
+    if opts.clip {
+        let end = line.timespan.start.msecs().max(line.timespan.end.msecs());
+        Some(TimePoint::from_msecs(end + opts.padding_ms() as i64))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Model, ModelFormat, RawChain};
+    use structopt::StructOpt;
+    use tempfile::TempDir;
+
+    const SUBTITLES: &str = "\
+[Script Info]
+Title: test
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+Dialogue: 0,0:00:01.00,0:00:03.00,Default,Alice,0,0,0,,hello there
+Dialogue: 0,0:00:04.00,0:00:06.00,Default,,0,0,0,,the cat sat on the mat
+Dialogue: 0,0:00:08.00,0:00:09.50,Default,Bob,0,0,0,,hello my friend
+";
+
+    /// Writes a model and reference subtitles to a temporary directory
+    fn setup() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut chain = RawChain::of_order(1);
+        for line in &["hello there my friend", "the cat sat on the mat"] {
+            chain.feed(&crate::tokenize(line));
+        }
+        let model_path = dir.path().join("model.yaml");
+        crate::save_model(
+            &Model::Chain(chain),
+            &model_path.to_string_lossy(),
+            ModelFormat::Yaml,
+        )
+        .unwrap();
+
+        std::fs::write(dir.path().join("ref.ass"), SUBTITLES).unwrap();
+        std::fs::create_dir(dir.path().join("out")).unwrap();
+        dir
+    }
+
+    /// Options for the fake renderer, saving every line with `--subtitles-out`
+    fn opts(dir: &TempDir, args: &[&str]) -> Screenshots {
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        let base = vec![
+            "screenshots".to_owned(),
+            format!("--model={}", path("model.yaml")),
+            "--video=video.mkv".to_owned(),
+            format!("--subtitles-ref={}", path("ref.ass")),
+            format!("--subtitles-out={}", path("out.ass")),
+            format!("--output-dir={}", path("out")),
+            "--renderer=fake".to_owned(),
+            "--all".to_owned(),
+            "--seed=1".to_owned(),
+        ];
+        Screenshots::from_iter(base.into_iter().chain(args.iter().map(|&s| s.to_owned())))
+    }
+
+    fn manifest(dir: &TempDir) -> Manifest {
+        Manifest::load(&dir.path().join("out").join("manifest.json")).unwrap()
+    }
+
+    #[test]
+    fn resume_saves_screenshots_without_records() {
+        let dir = setup();
+        generate_screenshots(&crate::NoopObserver, opts(&dir, &[])).unwrap();
+
+        // Simulate a run that was interrupted while saving the last screenshot
+        let mut interrupted = manifest(&dir);
+        assert_eq!(interrupted.screenshots.len(), 3);
+        let last = interrupted.screenshots.pop().unwrap();
+        interrupted
+            .save(&dir.path().join("out").join("manifest.json"))
+            .unwrap();
+        std::fs::write(&last.path, "").unwrap();
+        let first = &interrupted.screenshots[0].path;
+        std::fs::write(first, "already saved").unwrap();
+
+        generate_screenshots(&crate::NoopObserver, opts(&dir, &["--resume"])).unwrap();
+
+        let resumed = manifest(&dir);
+        assert_eq!(resumed.screenshots.len(), 3);
+        assert!(resumed
+            .screenshots
+            .iter()
+            .all(|record| record.score.is_some()));
+        assert_eq!(std::fs::read_to_string(first).unwrap(), "already saved");
+        assert!(std::fs::read_to_string(&last.path)
+            .unwrap()
+            .starts_with("frame at"));
+    }
+
+    #[test]
+    fn resume_rejects_changed_options() {
+        let dir = setup();
+        generate_screenshots(&crate::NoopObserver, opts(&dir, &[])).unwrap();
+
+        let args = ["--resume", "--quality=50", "--format=%m"];
+        let error = generate_screenshots(&crate::NoopObserver, opts(&dir, &args)).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Config);
+        assert!(error.to_string().ends_with("format, quality"), "{}", error);
+    }
+
+    #[test]
+    fn fake_renderer_saves_screenshots() {
+        let dir = setup();
+        generate_screenshots(&crate::NoopObserver, opts(&dir, &["--batch"])).unwrap();
+
+        let manifest = manifest(&dir);
+        assert_eq!(manifest.run.seed, 1);
+        assert_eq!(manifest.screenshots.len(), 3);
+
+        let originals = ["hello there", "the cat sat on the mat", "hello my friend"];
+        let actors = [Some("Alice"), None, Some("Bob")];
+        for (i, record) in manifest.screenshots.iter().enumerate() {
+            assert_eq!(record.original, originals[i]);
+            assert_eq!(record.actor.as_ref().map(String::as_str), actors[i]);
+            assert_eq!(record.style.as_ref().map(String::as_str), Some("Default"));
+            assert!(record.score.is_some());
+            assert_eq!(record.score, manifest.scores.get(&record.text).cloned());
+            assert_eq!(record.end_ms, None);
+
+            let path = Path::new(&record.path);
+            assert_eq!(path.parent(), Some(dir.path().join("out").as_path()));
+            assert_eq!(path.extension().unwrap(), "jpg");
+            let placeholder = std::fs::read_to_string(path).unwrap();
+            assert!(placeholder
+                .starts_with(&format!("frame at {} ms of video.mkv", record.timestamp_ms)));
+        }
+
+        // Each line's text is in the saved subtitles
+        let subtitles = std::fs::read_to_string(dir.path().join("out.ass")).unwrap();
+        assert!(manifest
+            .screenshots
+            .iter()
+            .all(|record| subtitles.contains(&record.text)));
+    }
+
+    #[test]
+    fn failed_screenshots_dont_stop_the_others() {
+        let dir = setup();
+        let args = ["--format=%m", "--jobs=3"];
+        generate_screenshots(&crate::NoopObserver, opts(&dir, &args)).unwrap();
+        let paths = manifest(&dir)
+            .screenshots
+            .into_iter()
+            .map(|record| record.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths.len(), 3);
+
+        // A directory in place of the second screenshot can't be written to
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+        std::fs::create_dir(&paths[1]).unwrap();
+
+        let error = generate_screenshots(&crate::NoopObserver, opts(&dir, &args)).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Io);
+        assert_eq!(error.to_string(), "failed to save 1 of 3 screenshots");
+
+        let saved = manifest(&dir)
+            .screenshots
+            .into_iter()
+            .map(|record| record.path)
+            .collect::<Vec<_>>();
+        assert_eq!(saved, vec![paths[0].clone(), paths[2].clone()]);
+    }
+
+    #[test]
+    fn fake_renderer_saves_clips() {
+        let dir = setup();
+        let args = ["--clip", "--clip-format=gif", "--padding=500"];
+        generate_screenshots(&crate::NoopObserver, opts(&dir, &args)).unwrap();
+
+        let manifest = manifest(&dir);
+        assert_eq!(manifest.screenshots.len(), 3);
+
+        let ends = [3500, 6500, 10000];
+        for (record, &end) in manifest.screenshots.iter().zip(&ends) {
+            assert_eq!(record.end_ms, Some(end));
+            assert!(record.path.ends_with(".gif"));
+            let placeholder = std::fs::read_to_string(&record.path).unwrap();
+            assert!(placeholder.starts_with(&format!(
+                "clip from {} ms to {} ms",
+                record.timestamp_ms, end
+            )));
+        }
+    }
 }
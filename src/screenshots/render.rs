@@ -0,0 +1,146 @@
+use crate::error::*;
+use crate::observer::Observer;
+use crate::opts::{ClipFormat, ImageFormat};
+use std::path::{Path, PathBuf};
+use subparse::timetypes::TimePoint;
+use subparse::SubtitleFormat;
+
+/// How rendered frames are encoded
+#[derive(Clone, Copy, Debug)]
+pub struct ImageOptions {
+    pub format: ImageFormat,
+    /// From 1 to 100 (higher is better), or `None` for the encoder's default
+    pub quality: Option<u32>,
+    /// Width and height to resize frames to. Either can be -1 to keep the
+    /// aspect ratio.
+    pub scale: Option<(i32, i32)>,
+    /// Frames wider than this are shrunk, keeping the aspect ratio
+    pub max_width: Option<u32>,
+}
+
+/// How rendered clips are encoded. Clips are resized like frames.
+#[derive(Clone, Copy, Debug)]
+pub struct ClipOptions {
+    pub format: ClipFormat,
+    /// Not supported for GIFs
+    pub audio: bool,
+    pub fps: Option<u32>,
+    /// Maximum size of a clip, in bytes
+    pub max_size: Option<u64>,
+}
+
+/// Renders frames of a video with subtitles burned in
+pub trait FrameRenderer: Send + Sync {
+    /// Extracts the first subtitle track embedded in `video`
+    fn extract_subtitles(
+        &self,
+        observer: &dyn Observer,
+        video: &str,
+    ) -> Result<(Vec<u8>, SubtitleFormat)>;
+
+    /// Saves the frame of `video` at `timestamp` to `output`, with the
+    /// subtitle file at `subtitles` burned in
+    fn render_frame(
+        &self,
+        observer: &dyn Observer,
+        video: &str,
+        subtitles: &str,
+        timestamp: TimePoint,
+        output: &Path,
+    ) -> Result<()>;
+
+    /// Saves the part of `video` from `start` to `end` to `output`, with the
+    /// subtitle file at `subtitles` burned in
+    fn render_clip(
+        &self,
+        observer: &dyn Observer,
+        video: &str,
+        subtitles: &str,
+        start: TimePoint,
+        end: TimePoint,
+        output: &Path,
+    ) -> Result<()>;
+
+    /// Returns the timestamps of the scene changes (e.g., cuts) in `video`
+    fn scene_changes(&self, observer: &dyn Observer, video: &str) -> Result<Vec<TimePoint>>;
+
+    /// Saves a frame for each `(timestamp, output)` pair, in any order.
+    /// Renderers that can render many frames faster than one at a time
+    /// should override this.
+    fn render_frames(
+        &self,
+        observer: &dyn Observer,
+        video: &str,
+        subtitles: &str,
+        frames: &[(TimePoint, PathBuf)],
+    ) -> Result<()> {
+        for (timestamp, output) in frames {
+            self.render_frame(observer, video, subtitles, *timestamp, output)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes a placeholder file describing each frame or clip, so screenshots can
+/// be saved without ffmpeg (e.g., to test the rest of the pipeline).
+/// Placeholders are always one line of plain text, even if the file name ends
+/// in an image or video extension: a valid image or video in every supported
+/// format would need an encoder for each, and couldn't show which frame was
+/// rendered, which is what the tests check.
+pub struct FakeRenderer;
+
+impl FrameRenderer for FakeRenderer {
+    fn extract_subtitles(
+        &self,
+        _observer: &dyn Observer,
+        _video: &str,
+    ) -> Result<(Vec<u8>, SubtitleFormat)> {
+        Err(Error::new(
+            ErrorKind::Config,
+            "the fake renderer can't extract subtitles (use --subtitles-ref instead)",
+        ))
+    }
+
+    fn scene_changes(&self, _observer: &dyn Observer, _video: &str) -> Result<Vec<TimePoint>> {
+        Ok(Vec::new())
+    }
+
+    fn render_frame(
+        &self,
+        _observer: &dyn Observer,
+        video: &str,
+        subtitles: &str,
+        timestamp: TimePoint,
+        output: &Path,
+    ) -> Result<()> {
+        let placeholder = format!(
+            "frame at {} ms of {} with subtitles {}\n",
+            timestamp.msecs(),
+            video,
+            subtitles
+        );
+
+        std::fs::write(output, placeholder).context(|| "failed to write placeholder frame")
+    }
+
+    fn render_clip(
+        &self,
+        _observer: &dyn Observer,
+        video: &str,
+        subtitles: &str,
+        start: TimePoint,
+        end: TimePoint,
+        output: &Path,
+    ) -> Result<()> {
+        let placeholder = format!(
+            "clip from {} ms to {} ms of {} with subtitles {}\n",
+            start.msecs(),
+            end.msecs(),
+            video,
+            subtitles
+        );
+
+        std::fs::write(output, placeholder).context(|| "failed to write placeholder clip")
+    }
+}
@@ -0,0 +1,273 @@
+use super::render::FrameRenderer;
+use super::SubtitleLine;
+use crate::error::*;
+use crate::observer::Observer;
+use crate::opts::{Screenshots, TimestampStrategy};
+use rand::Rng;
+use subparse::timetypes::TimePoint;
+
+/// Frames closer than this to a scene change are avoided by the `scene-aware`
+/// strategy, since they may show the end of the previous shot (or be blurry)
+const SCENE_CHANGE_MARGIN_MS: i64 = 300;
+
+/// Picks the timestamp of each line's screenshot with `--timestamp-strategy`,
+/// or the start of each line's clip with `--clip`
+pub fn pick_timestamps(
+    observer: &dyn Observer,
+    renderer: &dyn FrameRenderer,
+    opts: &Screenshots,
+    lines: Vec<SubtitleLine>,
+    rng: &mut impl Rng,
+) -> Result<Vec<(SubtitleLine, TimePoint)>> {
+    if opts.clip {
+        return Ok(clip_starts(lines, opts.padding_ms()));
+    }
+
+    let timestamps = match opts.timestamp_strategy {
+        TimestampStrategy::Random => lines
+            .into_iter()
+            .map(|line| {
+                let (start, end) = span_ms(&line);
+                let ts = random_between(start, end, rng);
+                (line, TimePoint::from_msecs(ts))
+            })
+            .collect(),
+        TimestampStrategy::Middle => lines
+            .into_iter()
+            .map(|line| {
+                let (start, end) = span_ms(&line);
+                (line, TimePoint::from_msecs(start + (end - start) / 2))
+            })
+            .collect(),
+        TimestampStrategy::Start(offset_ms) => lines
+            .into_iter()
+            .map(|line| {
+                let (start, end) = span_ms(&line);
+                let ts = (start + offset_ms as i64).min(end);
+                (line, TimePoint::from_msecs(ts))
+            })
+            .collect(),
+        TimestampStrategy::SceneAware => {
+            observer.info("Detecting scene changes", &[("path", &opts.video)]);
+            let scene_changes = renderer
+                .scene_changes(observer, &opts.video)?
+                .iter()
+                .map(|ts| ts.msecs())
+                .collect::<Vec<i64>>();
+            observer.info(
+                "Detected scene changes",
+                &[("scene_changes", &scene_changes.len())],
+            );
+
+            lines
+                .into_iter()
+                .map(|line| {
+                    let ts = scene_aware_timestamp(&line, &scene_changes, rng);
+                    (line, TimePoint::from_msecs(ts))
+                })
+                .collect()
+        }
+    };
+
+    Ok(timestamps)
+}
+
+/// Start of the clip of each line
+fn clip_starts(
+    lines: impl IntoIterator<Item = SubtitleLine>,
+    padding_ms: u32,
+) -> Vec<(SubtitleLine, TimePoint)> {
+    lines
+        .into_iter()
+        .map(|line| {
+            let (start, _) = span_ms(&line);
+            let start = (start - padding_ms as i64).max(0);
+            (line, TimePoint::from_msecs(start))
+        })
+        .collect()
+}
+
+/// Start and end of the line in milliseconds, in order
+fn span_ms(line: &SubtitleLine) -> (i64, i64) {
+    let start = line.timespan.start.msecs();
+    let end = line.timespan.end.msecs();
+    (start.min(end), start.max(end))
+}
+
+fn random_between(start: i64, end: i64, rng: &mut impl Rng) -> i64 {
+    if start >= end {
+        start
+    } else {
+        rng.gen_range(start, end)
+    }
+}
+
+/// Picks a random timestamp where the line is fully faded in, and that isn't
+/// close to a scene change. If there's no such timestamp, uses the middle of
+/// the part where the line is visible.
+fn scene_aware_timestamp(line: &SubtitleLine, scene_changes: &[i64], rng: &mut impl Rng) -> i64 {
+    let (start, end) = span_ms(line);
+    let (low, high) = match visible_span(&line.original, start, end) {
+        (low, high) if low < high => (low, high),
+        _ => (start, end),
+    };
+
+    // Remove the parts of the span around each scene change
+    let mut segments = vec![(low, high)];
+    for &change in scene_changes {
+        if change + SCENE_CHANGE_MARGIN_MS <= low || change - SCENE_CHANGE_MARGIN_MS >= high {
+            continue;
+        }
+
+        segments = segments
+            .into_iter()
+            .flat_map(|(a, b)| {
+                vec![
+                    (a, b.min(change - SCENE_CHANGE_MARGIN_MS)),
+                    (a.max(change + SCENE_CHANGE_MARGIN_MS), b),
+                ]
+            })
+            .filter(|(a, b)| a < b)
+            .collect();
+    }
+
+    let total = segments.iter().map(|(a, b)| b - a).sum::<i64>();
+    if total == 0 {
+        return low + (high - low) / 2;
+    }
+
+    // Each allowed timestamp is equally likely
+    let mut offset = random_between(0, total, rng);
+    for (a, b) in segments {
+        if offset < b - a {
+            return a + offset;
+        }
+        offset -= b - a;
+    }
+
+    unreachable!("offset is less than the total length of the segments")
+}
+
+/// Returns the part of a line from `start` to `end` where it's fully faded in,
+/// according to the `\fad` or `\fade` tag in its text
+fn visible_span(text: &str, start: i64, end: i64) -> (i64, i64) {
+    let tag = match text.find("\\fad") {
+        Some(index) => &text[index + "\\fad".len()..],
+        None => return (start, end),
+    };
+
+    let (is_complex, args) = if tag.starts_with("e(") {
+        (true, &tag[2..])
+    } else if tag.starts_with('(') {
+        (false, &tag[1..])
+    } else {
+        return (start, end);
+    };
+
+    let args = match args.find(')') {
+        Some(close) => args[..close]
+            .split(',')
+            .map(|arg| arg.trim().parse::<i64>().ok())
+            .collect::<Option<Vec<i64>>>(),
+        None => None,
+    };
+
+    match (is_complex, args) {
+        // \fad(fade_in, fade_out)
+        (false, Some(ref args)) if args.len() == 2 => (start + args[0], end - args[1]),
+        // \fade(a1, a2, a3, t1, t2, t3, t4): the middle alpha `a2` lasts from t2 to t3
+        (true, Some(ref args)) if args.len() == 7 => (start + args[4], start + args[5]),
+        _ => (start, end),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use subparse::timetypes::TimeSpan;
+
+    fn line(original: &str, start: i64, end: i64) -> SubtitleLine {
+        SubtitleLine {
+            timespan: TimeSpan::new(TimePoint::from_msecs(start), TimePoint::from_msecs(end)),
+            text: "generated".to_owned(),
+            original: original.to_owned(),
+            style: None,
+            actor: None,
+        }
+    }
+
+    /// Timestamps picked for `line` with many different seeds
+    fn scene_aware_timestamps(line: &SubtitleLine, scene_changes: &[i64]) -> Vec<i64> {
+        (0..200)
+            .map(|seed| {
+                let mut rng = StdRng::seed_from_u64(seed);
+                scene_aware_timestamp(line, scene_changes, &mut rng)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fad_trims_fades() {
+        assert_eq!(
+            visible_span("{\\fad(200,300)}hello", 1000, 3000),
+            (1200, 2700)
+        );
+        assert_eq!(
+            visible_span("{\\an8\\fad( 200 , 0 )}hello", 1000, 3000),
+            (1200, 3000)
+        );
+    }
+
+    #[test]
+    fn fade_uses_the_middle_alpha() {
+        assert_eq!(
+            visible_span("{\\fade(255,0,255,0,500,1500,2000)}hello", 1000, 4000),
+            (1500, 2500)
+        );
+    }
+
+    #[test]
+    fn invalid_fades_are_ignored() {
+        for text in &[
+            "hello",
+            "{\\fad(200)}hello",
+            "{\\fad(200,x)}hello",
+            "{\\fad(200,300}hello",
+            "{\\fade(255,0,255,0,500,1500)}hello",
+            "{\\fad}hello",
+        ] {
+            assert_eq!(visible_span(text, 1000, 3000), (1000, 3000), "{}", text);
+        }
+    }
+
+    #[test]
+    fn scene_aware_stays_in_the_visible_span() {
+        let line = line("{\\fad(500,500)}hello", 1000, 3000);
+        for ts in scene_aware_timestamps(&line, &[]) {
+            assert!(ts >= 1500 && ts < 2500, "{}", ts);
+        }
+    }
+
+    #[test]
+    fn scene_aware_avoids_scene_changes() {
+        let line = line("hello", 1000, 3000);
+        let timestamps = scene_aware_timestamps(&line, &[500, 2000, 5000]);
+        for &ts in &timestamps {
+            assert!(ts >= 1000 && ts < 3000, "{}", ts);
+            assert!((ts - 2000).abs() >= SCENE_CHANGE_MARGIN_MS, "{}", ts);
+        }
+
+        // Both sides of the scene change can be picked
+        assert!(timestamps.iter().any(|&ts| ts < 2000));
+        assert!(timestamps.iter().any(|&ts| ts > 2000));
+    }
+
+    #[test]
+    fn scene_aware_falls_back_to_the_middle() {
+        // Every timestamp of the line is close to a scene change
+        let line = line("{\\fad(100,100)}hello", 1000, 1600);
+        assert_eq!(scene_aware_timestamps(&line, &[1300]), vec![1300; 200]);
+    }
+}
@@ -0,0 +1,486 @@
+use crate::error::*;
+use crate::filter::{load_filter, ContentFilter};
+use crate::generate::{GeneratedLine, Generator};
+use crate::model::Model;
+use crate::observer::Observer;
+use crate::opts;
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::sync::Arc;
+use subparse::SubtitleFile;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Upper limit for the `count` param, so a single request can't take forever
+const MAX_COUNT: usize = 100;
+/// Upper limit for the `candidates` param, for the same reason
+const MAX_CANDIDATES: usize = 20;
+/// Upper limit for the `min_length` param, in characters
+const MAX_MIN_LENGTH: usize = 1000;
+/// Upper limit for the size of a `POST /subtitles` body, in bytes
+const MAX_BODY_LENGTH: usize = 10 * 1024 * 1024;
+
+struct NamedModel {
+    name: String,
+    model: Arc<Model>,
+}
+
+#[derive(Serialize)]
+struct ModelInfo<'a> {
+    name: &'a str,
+    order: usize,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// Error with the HTTP status code to respond with
+struct HttpError {
+    status: u16,
+    error: Error,
+}
+
+impl HttpError {
+    fn bad_request<S: Into<String>>(message: S) -> Self {
+        HttpError {
+            status: 400,
+            error: Error::context(message.into()),
+        }
+    }
+
+    fn too_large<S: Into<String>>(message: S) -> Self {
+        HttpError {
+            status: 413,
+            error: Error::context(message.into()),
+        }
+    }
+
+    fn not_found<S: Into<String>>(message: S) -> Self {
+        HttpError {
+            status: 404,
+            error: Error::context(message.into()),
+        }
+    }
+}
+
+impl From<Error> for HttpError {
+    fn from(error: Error) -> Self {
+        // These are caused by the request's params or body
+        let status = match error.kind() {
+            ErrorKind::Parse | ErrorKind::Config | ErrorKind::Constraint => 400,
+            _ => 500,
+        };
+
+        HttpError { status, error }
+    }
+}
+
+type HttpResult = std::result::Result<Response<Cursor<Vec<u8>>>, HttpError>;
+
+/// Loads the models, then responds to HTTP requests until the process is killed.
+///
+/// Endpoints:
+/// * `GET /models`: lists the loaded models
+/// * `GET /generate`: generates lines of text as a JSON array (see `Generator::lines`)
+/// * `POST /subtitles`: replaces the text of the subtitle file in the request body
+pub fn serve(observer: &dyn Observer, args: opts::Serve) -> Result<()> {
+    let mut models = args
+        .models
+        .iter()
+        .map(|arg| load_named_model(observer, arg, args.generation.min_support))
+        .collect::<Result<Vec<NamedModel>>>()?;
+
+    let filter = load_filter(
+        observer,
+        args.generation.blocklist.as_ref().map(|s| s.as_ref()),
+        args.generation.max_retries,
+    )?;
+    if args.generation.remove_blocked_tokens {
+        for named in models.iter_mut() {
+            // Models aren't shared until requests are handled
+            let model = Arc::get_mut(&mut named.model).expect("model is not shared");
+            filter.apply_to_model(observer, model)?;
+        }
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs)
+        .build()
+        .context_kind(ErrorKind::Io, || "failed to create thread pool")?;
+
+    let server = Server::http(&args.address)
+        .map_err(|e| Error::new(ErrorKind::Io, format!("failed to start server: {}", e)))?;
+    observer.info(
+        "Listening for requests",
+        &[
+            ("address", &server.server_addr()),
+            ("jobs", &pool.current_num_threads()),
+        ],
+    );
+
+    // Each thread waits for its next request, so a slow request (e.g., a long
+    // subtitle file) only holds up its own thread
+    let (models, filter, defaults) = (&models, &filter, &args.generation);
+    pool.scope(|scope| {
+        for _ in 0..pool.current_num_threads() {
+            scope.spawn(|_| {
+                for request in server.incoming_requests() {
+                    respond(observer, models, filter, defaults, request);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Handles a request, logging it and sending the response (or an error).
+/// Params missing from the request are taken from `defaults`.
+fn respond(
+    observer: &dyn Observer,
+    models: &[NamedModel],
+    filter: &ContentFilter,
+    defaults: &opts::GenerationOpts,
+    mut request: Request,
+) {
+    let (status, response) = match handle_request(models, filter, defaults, &mut request) {
+        Ok(response) => (200, response),
+        Err(err) => {
+            observer.warn("Failed to handle request", &[("description", &err.error)]);
+            let body = ErrorBody {
+                error: err
+                    .error
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join(": "),
+            };
+            (
+                err.status,
+                json_response(&body).with_status_code(err.status),
+            )
+        }
+    };
+
+    observer.info(
+        "Handled request",
+        &[
+            ("method", &request.method()),
+            ("url", &request.url()),
+            ("status", &status),
+        ],
+    );
+
+    if let Err(e) = request.respond(response) {
+        observer.warn("Failed to send response", &[("description", &e)]);
+    }
+}
+
+/// Loads a model from `PATH` or `NAME=PATH`. If no name is given, the file name
+/// (without its extension) is used.
+fn load_named_model(observer: &dyn Observer, arg: &str, min_support: usize) -> Result<NamedModel> {
+    let mut parts = arg.splitn(2, '=');
+    let (name, path) = match (parts.next(), parts.next()) {
+        (Some(name), Some(path)) => (name.to_owned(), path),
+        _ => {
+            let name = Path::new(arg)
+                .file_stem()
+                .map_or(arg.into(), |stem| stem.to_string_lossy());
+            (name.into_owned(), arg)
+        }
+    };
+
+    observer.info(
+        "Loading model from file",
+        &[("name", &name), ("path", &path)],
+    );
+    let mut model = crate::load_model(path)?;
+    model.set_min_support(min_support);
+
+    Ok(NamedModel {
+        name,
+        model: Arc::new(model),
+    })
+}
+
+fn handle_request(
+    models: &[NamedModel],
+    filter: &ContentFilter,
+    defaults: &opts::GenerationOpts,
+    request: &mut Request,
+) -> HttpResult {
+    let (path, query) = {
+        let mut parts = request.url().splitn(2, '?');
+        (
+            parts.next().unwrap_or("").to_owned(),
+            parts.next().unwrap_or("").to_owned(),
+        )
+    };
+
+    let params = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+
+    match (request.method(), path.as_ref()) {
+        (Method::Get, "/models") => {
+            let info = models
+                .iter()
+                .map(|named| ModelInfo {
+                    name: &named.name,
+                    order: named.model.order(),
+                })
+                .collect::<Vec<_>>();
+            Ok(json_response(&info))
+        }
+        (Method::Get, "/generate") => {
+            let count = bounded_param(&params, "count", MAX_COUNT)?.unwrap_or(1);
+
+            let lines = build_generator(models, filter, defaults, &params)?
+                .lines()
+                .take(count)
+                .collect::<Result<Vec<GeneratedLine>>>()?;
+
+            Ok(json_response(&lines))
+        }
+        (Method::Post, "/subtitles") => {
+            let mut generator = build_generator(models, filter, defaults, &params)?;
+            let extension = params
+                .get("format")
+                .ok_or_else(|| HttpError::bad_request("missing param: format"))?;
+            let format =
+                subparse::get_subtitle_format_by_ending_err(&format!("subtitles.{}", extension))
+                    .map_err(|_| HttpError::bad_request("unknown subtitle format"))?;
+
+            let too_large = || {
+                HttpError::too_large(format!(
+                    "request body must be at most {} bytes",
+                    MAX_BODY_LENGTH
+                ))
+            };
+            if request
+                .body_length()
+                .map_or(false, |len| len > MAX_BODY_LENGTH)
+            {
+                return Err(too_large());
+            }
+
+            // The length header is optional, so the body is limited while reading too
+            let mut body = Vec::new();
+            request
+                .as_reader()
+                .take(MAX_BODY_LENGTH as u64 + 1)
+                .read_to_end(&mut body)
+                .context(|| "failed to read request body")?;
+            if body.len() > MAX_BODY_LENGTH {
+                return Err(too_large());
+            }
+
+            let mut file = crate::train::parse_subtitles(&mut body.as_slice(), format, true)?;
+
+            generator.generate_subtitle_file(&mut file)?;
+
+            let data = file
+                .to_data()
+                .context_kind(ErrorKind::Parse, || "failed to serialize subtitle data")?;
+            Ok(Response::from_data(data).with_header(content_type("text/plain; charset=utf-8")))
+        }
+        (_, "/models") | (_, "/generate") | (_, "/subtitles") => Err(HttpError {
+            status: 405,
+            error: Error::context("method not allowed"),
+        }),
+        _ => Err(HttpError::not_found("not found")),
+    }
+}
+
+/// Builds a generator from the request's params. Uses the model named by the
+/// `model` param, or the first model if unspecified.
+fn build_generator(
+    models: &[NamedModel],
+    filter: &ContentFilter,
+    defaults: &opts::GenerationOpts,
+    params: &HashMap<String, String>,
+) -> std::result::Result<Generator, HttpError> {
+    let named = match params.get("model") {
+        Some(name) => models.iter().find(|named| &named.name == name),
+        None => models.first(),
+    };
+    let model = named
+        .map(|named| named.model.clone())
+        .ok_or_else(|| HttpError::not_found("model not found"))?;
+
+    let temperature = param(params, "temperature")?.unwrap_or(defaults.temperature);
+    crate::generate::check_temperature(temperature)?;
+
+    let generator = Generator::builder()
+        .model(model)
+        .start_token(params.get("start").cloned())
+        .min_length(bounded_param(params, "min_length", MAX_MIN_LENGTH)?.or(defaults.min_length))
+        .temperature(temperature)
+        .candidates(
+            bounded_param(params, "candidates", MAX_CANDIDATES)?.unwrap_or(defaults.candidates),
+        )
+        .filter(filter.clone())
+        .seed(param(params, "seed")?)
+        .build()?;
+
+    Ok(generator)
+}
+
+fn param<T: std::str::FromStr>(
+    params: &HashMap<String, String>,
+    name: &str,
+) -> std::result::Result<Option<T>, HttpError> {
+    match params.get(name) {
+        None => Ok(None),
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| HttpError::bad_request(format!("invalid value for param: {}", name))),
+    }
+}
+
+/// Like `param`, but rejects values greater than `max`
+fn bounded_param(
+    params: &HashMap<String, String>,
+    name: &str,
+    max: usize,
+) -> std::result::Result<Option<usize>, HttpError> {
+    match param(params, name)? {
+        Some(value) if value > max => Err(HttpError::bad_request(format!(
+            "{} must be at most {}",
+            name, max
+        ))),
+        value => Ok(value),
+    }
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).expect("valid header")
+}
+
+fn json_response<T: serde::Serialize>(value: &T) -> Response<Cursor<Vec<u8>>> {
+    // Serializing these types can't fail, since they only contain strings and numbers
+    let body = serde_json::to_vec(value).expect("serializable response");
+    Response::from_data(body).with_header(content_type("application/json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::RawChain;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpStream};
+    use structopt::StructOpt;
+
+    /// Starts a server on an ephemeral port that handles `requests` requests
+    fn start_server(requests: usize) -> SocketAddr {
+        let mut chain = RawChain::of_order(1);
+        chain.feed(&["hello".to_owned(), "world".to_owned()]);
+        let models = vec![NamedModel {
+            name: "test".to_owned(),
+            model: Arc::new(Model::Chain(chain)),
+        }];
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr();
+        std::thread::spawn(move || {
+            let filter = ContentFilter::default();
+            let defaults = opts::GenerationOpts::from_iter(&["subkatsu"]);
+            for request in server.incoming_requests().take(requests) {
+                respond(&crate::NoopObserver, &models, &filter, &defaults, request);
+            }
+        });
+
+        address
+    }
+
+    /// Sends a request, returning the status code and body
+    fn request(address: SocketAddr, head: &str, body: Vec<u8>) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{}\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            head
+        )
+        .unwrap();
+
+        // The server may respond before reading the whole body, so it's sent
+        // separately. Errors are ignored, since the server can close the
+        // connection early.
+        let mut writer = stream.try_clone().unwrap();
+        let sender = std::thread::spawn(move || {
+            let _ = writer.write_all(&body);
+        });
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        sender.join().unwrap();
+
+        let status = response[9..12].parse().unwrap();
+        let body = response
+            .splitn(2, "\r\n\r\n")
+            .nth(1)
+            .unwrap_or("")
+            .to_owned();
+        (status, body)
+    }
+
+    fn get(address: SocketAddr, url: &str) -> (u16, String) {
+        request(address, &format!("GET {} HTTP/1.1", url), Vec::new())
+    }
+
+    #[test]
+    fn generates_lines() {
+        let address = start_server(2);
+
+        let (status, body) = get(address, "/models");
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"[{"name":"test","order":1}]"#);
+
+        let (status, body) = get(address, "/generate?count=2&candidates=2&seed=1");
+        assert_eq!(status, 200);
+        let lines: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["text"], "hello world");
+    }
+
+    #[test]
+    fn rejects_out_of_range_params() {
+        let urls = [
+            "/generate?count=101",
+            "/generate?candidates=21",
+            "/generate?min_length=1001",
+            "/generate?temperature=-1",
+            "/generate?temperature=NaN",
+            "/generate?count=many",
+        ];
+        let address = start_server(urls.len());
+
+        for url in &urls {
+            let (status, body) = get(address, url);
+            assert_eq!(status, 400, "{}: {}", url, body);
+        }
+    }
+
+    #[test]
+    fn rejects_large_bodies() {
+        let address = start_server(2);
+        let url = "POST /subtitles?format=ass HTTP/1.1";
+
+        let body = vec![b'a'; MAX_BODY_LENGTH + 1];
+        let head = format!("{}\r\nContent-Length: {}", url, body.len());
+        let (status, _) = request(address, &head, body);
+        assert_eq!(status, 413);
+
+        // Without a length, the body is only limited while it's read
+        let head = format!("{}\r\nTransfer-Encoding: chunked", url);
+        let mut body = format!("{:x}\r\n", MAX_BODY_LENGTH + 1).into_bytes();
+        body.extend(std::iter::repeat(b'a').take(MAX_BODY_LENGTH + 1));
+        body.extend_from_slice(b"\r\n0\r\n\r\n");
+        let (status, _) = request(address, &head, body);
+        assert_eq!(status, 413);
+    }
+}
@@ -1,12 +1,16 @@
 use crate::error::*;
+use crate::model::{BackoffModel, Model, RawChain};
+use crate::observer::Observer;
 use crate::opts;
 use lazy_static::lazy_static;
+use rayon::prelude::*;
 use regex::Regex;
-use slog::Logger;
+use serde_derive::Serialize;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Write};
 use std::path::PathBuf;
 use subparse::{GenericSubtitleFile, SubtitleFile, SubtitleFormat};
 
@@ -61,7 +65,7 @@ pub fn tokenize(input: &str) -> Vec<String> {
 
 pub fn get_subtitles_from_file(path: &str, sanitize: bool) -> Result<GenericSubtitleFile> {
     let format = subparse::get_subtitle_format_by_ending_err(path)
-        .context(|| "failed to determine subtitle format")?;
+        .context_kind(ErrorKind::Parse, || "failed to determine subtitle format")?;
 
     let mut file = File::open(path).context(|| "failed to read file")?;
 
@@ -89,11 +93,12 @@ pub fn parse_subtitles(
             .context(|| "failed to write line")?;
     }
 
-    subparse::parse_str(format, &output, 24.0).context(|| "failed to parse subtitle file")
+    subparse::parse_str(format, &output, 24.0)
+        .context_kind(ErrorKind::Parse, || "failed to parse subtitle file")
 }
 
 fn iterate_files(
-    log: &Logger,
+    observer: &dyn Observer,
     path: String,
     recursive: bool,
 ) -> impl Iterator<Item = PathBuf> + '_ {
@@ -110,10 +115,9 @@ fn iterate_files(
                 let reason = err
                     .io_error()
                     .map_or("unknown".to_string(), |e| e.to_string());
-                slog::warn!(
-                    log, "Failed to handle file";
-                    "path" => %path,
-                    "reason" => reason
+                observer.warn(
+                    "Failed to handle file",
+                    &[("path", &path), ("reason", &reason)],
                 );
                 return None;
             }
@@ -121,10 +125,12 @@ fn iterate_files(
 
         if entry.file_type().is_dir() {
             if !recursive {
-                slog::warn!(
-                    log, "Ignoring directory";
-                    "reason" => "--recursive is not specified",
-                    "path" => %entry.path().to_string_lossy()
+                observer.warn(
+                    "Ignoring directory",
+                    &[
+                        ("reason", &"--recursive is not specified"),
+                        ("path", &entry.path().to_string_lossy()),
+                    ],
                 );
             }
             return None;
@@ -140,82 +146,610 @@ fn hash<T: Hash>(obj: T) -> u64 {
     hasher.finish()
 }
 
-pub fn train(log: &Logger, args: opts::Train) -> Result<()> {
-    let mut chain = markov::Chain::of_order(args.order);
+/// Reason for a subtitle line to be excluded from the training data
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropReason {
+    /// `Comment:` event in an SSA/ASS file
+    Comment,
+    /// Nothing was left after stripping escape codes (e.g., hidden typesetting text)
+    Escapes,
+    /// Tokenizes the same as the line before it
+    Duplicate,
+    /// Tokenizes the same as a line fed earlier in the training run
+    Repeated,
+    /// Tokenizes the same as a line fed earlier in the training run,
+    /// ignoring case and punctuation
+    NearDuplicate,
+    /// Line has no tokens
+    Empty,
+}
 
-    let recursive = args.recursive;
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::Comment => "comment",
+            DropReason::Escapes => "escapes",
+            DropReason::Duplicate => "duplicate",
+            DropReason::Repeated => "repeated",
+            DropReason::NearDuplicate => "near_duplicate",
+            DropReason::Empty => "empty",
+        }
+    }
+}
 
-    let paths = args
-        .input
-        .into_iter()
-        .flat_map(|path| iterate_files(log, path, recursive));
+#[derive(Debug, Serialize)]
+pub struct SanitizedLine {
+    pub text: String,
+    pub tokens: Vec<String>,
+    /// If `None`, the line is fed to the model
+    pub dropped: Option<DropReason>,
+}
 
-    let mut processed_files = 0;
-    let mut skipped_files = 0;
-    for path_buf in paths {
-        let path = match path_buf.to_str() {
-            Some(p) => p,
-            None => {
-                slog::warn!(log, "failed to parse path"; "path" => %path_buf.to_string_lossy());
+#[derive(Debug, Serialize)]
+pub struct SanitizedFile {
+    pub path: String,
+    pub lines: Vec<SanitizedLine>,
+}
+
+impl SanitizedFile {
+    pub fn fed_tokens(self) -> impl Iterator<Item = Vec<String>> {
+        self.lines
+            .into_iter()
+            .filter(|line| line.dropped.is_none())
+            .map(|line| line.tokens)
+    }
+}
+
+/// Drops lines that were already fed earlier in the training run (e.g., OP/ED
+/// lyrics, recaps, or the same episode from different releases)
+#[derive(Debug)]
+pub struct Deduplicator {
+    mode: opts::Dedup,
+    seen: HashSet<u64>,
+    seen_normalized: HashSet<u64>,
+}
+
+impl Deduplicator {
+    pub fn new(mode: opts::Dedup) -> Self {
+        Deduplicator {
+            mode,
+            seen: HashSet::new(),
+            seen_normalized: HashSet::new(),
+        }
+    }
+
+    pub fn apply(&mut self, file: &mut SanitizedFile) {
+        if self.mode == opts::Dedup::Adjacent {
+            return;
+        }
+
+        for line in file.lines.iter_mut() {
+            if line.dropped.is_some() {
                 continue;
             }
-        };
 
-        // Don't quit the whole function on error, just continue
-        let subs: Result<Vec<subparse::SubtitleEntry>> = (|| {
-            Ok(get_subtitles_from_file(path, true)?
-                .get_subtitle_entries()
-                .context(|| "failed to get subtitle entries")?)
-        })();
-
-        let subs = match subs {
-            Ok(s) => s,
-            Err(s) => {
-                slog::warn!(
-                    log, "Skipping file";
-                    "reason" => %s, "path" => path
-                );
-                skipped_files = skipped_files + 1;
-                continue;
+            let is_new = self.seen.insert(hash(&line.tokens));
+
+            if !is_new {
+                line.dropped = Some(DropReason::Repeated);
+            } else if self.mode == opts::Dedup::Near
+                && !self.seen_normalized.insert(hash(normalize(&line.tokens)))
+            {
+                line.dropped = Some(DropReason::NearDuplicate);
             }
-        };
+        }
+    }
+}
+
+/// Lowercases tokens and strips punctuation, so that lines differing only in
+/// capitalization or punctuation are considered equal
+fn normalize(tokens: &[String]) -> Vec<String> {
+    tokens
+        .iter()
+        .map(|token| {
+            token
+                .chars()
+                .filter(|c| c.is_alphanumeric())
+                .flat_map(|c| c.to_lowercase())
+                .collect::<String>()
+        })
+        .filter(|token| !token.is_empty())
+        .collect()
+}
 
-        let mut prev_tokens_hash = 0;
+/// Number of lines fed to the model, and dropped for each reason
+#[derive(Debug, Default)]
+struct LineCounts {
+    fed: usize,
+    comment: usize,
+    escapes: usize,
+    duplicate: usize,
+    repeated: usize,
+    near_duplicate: usize,
+    empty: usize,
+}
 
-        for entry in subs {
-            if let Some(line) = entry.line {
-                let tokens = tokenize(&line);
-                let tokens_hash = hash(&tokens);
+impl LineCounts {
+    fn add(&mut self, file: &SanitizedFile) {
+        for line in &file.lines {
+            let count = match line.dropped {
+                None => &mut self.fed,
+                Some(DropReason::Comment) => &mut self.comment,
+                Some(DropReason::Escapes) => &mut self.escapes,
+                Some(DropReason::Duplicate) => &mut self.duplicate,
+                Some(DropReason::Repeated) => &mut self.repeated,
+                Some(DropReason::NearDuplicate) => &mut self.near_duplicate,
+                Some(DropReason::Empty) => &mut self.empty,
+            };
+            *count += 1;
+        }
+    }
+}
 
+/// Reads a subtitle file and decides which lines should be used as training data
+pub fn sanitize_file(path: &str) -> Result<SanitizedFile> {
+    let format = subparse::get_subtitle_format_by_ending_err(path)
+        .context_kind(ErrorKind::Parse, || "failed to determine subtitle format")?;
+
+    let contents = std::fs::read_to_string(path).context(|| "failed to read file")?;
+
+    // Comments are stripped before parsing, so they need to be collected
+    // separately, along with the number of dialogue events before each one
+    let mut comments = Vec::new();
+    if format == SubtitleFormat::SubStationAlpha {
+        let mut dialogue_count = 0;
+        for line in contents.lines() {
+            if line.starts_with("Dialogue: ") {
+                dialogue_count += 1;
+            } else if line.starts_with("Comment: ") {
+                // The text is the last of the 10 comma-separated fields
+                let text = line.splitn(10, ',').nth(9).unwrap_or("");
+                let comment = SanitizedLine {
+                    text: text.to_owned(),
+                    tokens: tokenize(text),
+                    dropped: Some(DropReason::Comment),
+                };
+                comments.push((dialogue_count, comment));
+            }
+        }
+    }
+    let mut comments = comments.into_iter().peekable();
+
+    let entries = parse_subtitles(&mut contents.as_bytes(), format, true)?
+        .get_subtitle_entries()
+        .context_kind(ErrorKind::Parse, || "failed to get subtitle entries")?;
+
+    let mut lines = Vec::new();
+    let mut prev_tokens_hash = 0;
+
+    for (index, entry) in entries.into_iter().enumerate() {
+        // Keep the lines in the same order as in the file
+        while let Some(&(before, _)) = comments.peek() {
+            if before > index {
+                break;
+            }
+            lines.extend(comments.next().map(|(_, comment)| comment));
+        }
+
+        if let Some(text) = entry.line {
+            let tokens = tokenize(&text);
+            let tokens_hash = hash(&tokens);
+
+            let dropped = if tokens.is_empty() {
+                if ESCAPES.is_match(&text) {
+                    Some(DropReason::Escapes)
+                } else {
+                    Some(DropReason::Empty)
+                }
+            } else if tokens_hash == prev_tokens_hash {
                 // Sometimes lines are duplicated for typesetting purposes.
                 // E.g., for a typeset title, the subs may contain the title
                 // repeated 3 times but on different layers, each with different
                 // styles. In these cases, we don't want to put extra weight
                 // on these lines, so only feed them once.
-                if tokens_hash != prev_tokens_hash {
-                    prev_tokens_hash = tokens_hash;
-                    chain.feed(tokens);
-                }
+                Some(DropReason::Duplicate)
+            } else {
+                None
+            };
+
+            prev_tokens_hash = tokens_hash;
+
+            lines.push(SanitizedLine {
+                text,
+                tokens,
+                dropped,
+            });
+        }
+    }
+    lines.extend(comments.map(|(_, comment)| comment));
+
+    Ok(SanitizedFile {
+        path: path.to_owned(),
+        lines,
+    })
+}
+
+fn write_report(
+    file: &SanitizedFile,
+    format: opts::ReportFormat,
+    output: &mut impl Write,
+) -> Result<()> {
+    match format {
+        opts::ReportFormat::Text => {
+            writeln!(output, "{}", file.path).context(|| "failed to write to output")?;
+            for line in &file.lines {
+                let status = line.dropped.map_or("fed", |reason| reason.as_str());
+                writeln!(
+                    output,
+                    "  {:<9} {:?} => {:?}",
+                    status, line.text, line.tokens
+                )
+                .context(|| "failed to write to output")?;
             }
         }
+        opts::ReportFormat::Json => {
+            serde_json::to_writer(&mut *output, file)
+                .context_kind(ErrorKind::Io, || "failed to serialize report")?;
+            writeln!(output).context(|| "failed to write to output")?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn train(observer: &dyn Observer, args: opts::Train, output: &mut impl Write) -> Result<()> {
+    // Fail before reading any files, rather than after training the whole model
+    crate::model::check_orders(&args.order)?;
+    if !args.dry_run {
+        args.model_format.check_orders(args.order.len())?;
+    }
+
+    let mut chains = args
+        .order
+        .iter()
+        .map(|&order| RawChain::of_order(order))
+        .collect::<Vec<RawChain>>();
+
+    let recursive = args.recursive;
 
-        slog::info!(log, "Processed file"; "path" => path);
+    let paths = args
+        .input
+        .into_iter()
+        .flat_map(|path| iterate_files(observer, path, recursive))
+        .filter_map(|path_buf| match path_buf.into_os_string().into_string() {
+            Ok(path) => Some(path),
+            Err(path) => {
+                observer.warn("failed to parse path", &[("path", &path.to_string_lossy())]);
+                None
+            }
+        })
+        .collect::<Vec<String>>();
 
-        processed_files = processed_files + 1;
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs)
+        .build()
+        .context_kind(ErrorKind::Io, || "failed to create thread pool")?;
+
+    let mut dedup = Deduplicator::new(args.dedup);
+    let mut counts = LineCounts::default();
+
+    let mut processed_files = 0;
+    let mut skipped_files = 0;
+
+    // Files are sanitized in parallel, but deduplicated and fed in input order,
+    // so the resulting model is the same regardless of the number of jobs
+    for chunk in paths.chunks(pool.current_num_threads() * 4) {
+        let files: Vec<std::result::Result<SanitizedFile, String>> = pool.install(|| {
+            chunk
+                .par_iter()
+                .map(|path| sanitize_file(path).map_err(|e| e.to_string()))
+                .collect()
+        });
+
+        for (path, file) in chunk.iter().zip(files) {
+            // Don't quit the whole function on error, just continue
+            let mut file = match file {
+                Ok(f) => f,
+                Err(s) => {
+                    observer.warn("Skipping file", &[("reason", &s), ("path", &path)]);
+                    skipped_files = skipped_files + 1;
+                    continue;
+                }
+            };
+
+            dedup.apply(&mut file);
+            counts.add(&file);
+
+            if args.dry_run {
+                write_report(&file, args.report_format, output)?;
+            } else {
+                for tokens in file.fed_tokens() {
+                    for chain in chains.iter_mut() {
+                        chain.feed(&tokens);
+                    }
+                }
+            }
+
+            observer.info("Processed file", &[("path", &path)]);
+
+            processed_files = processed_files + 1;
+        }
     }
 
     if processed_files == 0 {
-        return Err(Error::context("No files processed"));
+        return Err(Error::new(ErrorKind::NoInput, "No files processed"));
     }
 
-    slog::info!(
-        log, "Processed input files";
-        "skipped" => skipped_files, "count" => processed_files
+    observer.info(
+        "Processed input files",
+        &[("skipped", &skipped_files), ("count", &processed_files)],
+    );
+    observer.info(
+        "Processed input lines",
+        &[
+            ("fed", &counts.fed),
+            ("comment", &counts.comment),
+            ("escapes", &counts.escapes),
+            ("duplicate", &counts.duplicate),
+            ("repeated", &counts.repeated),
+            ("near_duplicate", &counts.near_duplicate),
+            ("empty", &counts.empty),
+        ],
     );
-    slog::info!(log, "Saving model to file"; "path" => &args.output);
-    chain
-        .save(&args.output)
-        .context(|| "failed to save model file")?;
 
-    Ok(())
+    let model_path = match args.output {
+        Some(ref path) if !args.dry_run => path,
+        _ => return Ok(()),
+    };
+
+    let mut model = if chains.len() == 1 {
+        Model::Chain(chains.remove(0))
+    } else {
+        Model::Backoff(BackoffModel::new(chains))
+    };
+
+    if args.min_count > 1 {
+        model = crate::prune_model(observer, model, args.min_count)?;
+    }
+
+    observer.info("Saving model to file", &[("path", &model_path)]);
+    crate::save_model(&model, model_path, args.model_format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUBTITLES: &str = "\
+[Script Info]
+ScriptType: v4.00+
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+Comment: 0,0:00:00.00,0:00:01.00,Default,,0,0,0,,Opening note
+Dialogue: 0,0:00:01.00,0:00:02.00,Default,,0,0,0,,Hello there
+Comment: 0,0:00:02.00,0:00:03.00,Default,,0,0,0,,Middle note
+Dialogue: 0,0:00:03.00,0:00:04.00,Default,,0,0,0,,Hello there
+Dialogue: 0,0:00:04.00,0:00:05.00,Default,,0,0,0,,{\\pos(1,1)}
+Comment: 0,0:00:05.00,0:00:06.00,Default,,0,0,0,,Closing note
+";
+
+    fn sanitize(contents: &str) -> SanitizedFile {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("subtitles.ass");
+        std::fs::write(&path, contents).unwrap();
+        sanitize_file(path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn sanitize_keeps_file_order() {
+        let file = sanitize(SUBTITLES);
+        let lines = file
+            .lines
+            .iter()
+            .map(|line| (line.text.as_str(), line.dropped))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            lines,
+            vec![
+                ("Opening note", Some(DropReason::Comment)),
+                ("Hello there", None),
+                ("Middle note", Some(DropReason::Comment)),
+                ("Hello there", Some(DropReason::Duplicate)),
+                ("{\\pos(1,1)}", Some(DropReason::Escapes)),
+                ("Closing note", Some(DropReason::Comment)),
+            ]
+        );
+    }
+
+    fn file(lines: &[&str]) -> SanitizedFile {
+        SanitizedFile {
+            path: "subtitles.ass".to_owned(),
+            lines: lines
+                .iter()
+                .map(|text| SanitizedLine {
+                    text: text.to_string(),
+                    tokens: tokenize(text),
+                    dropped: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn dropped(file: &SanitizedFile) -> Vec<Option<DropReason>> {
+        file.lines.iter().map(|line| line.dropped).collect()
+    }
+
+    #[test]
+    fn normalize_ignores_case_and_punctuation() {
+        assert_eq!(
+            normalize(&tokenize("Wait... WHAT?!")),
+            normalize(&tokenize("wait what"))
+        );
+        assert_eq!(normalize(&tokenize("...!")), Vec::<String>::new());
+        assert_ne!(
+            normalize(&tokenize("I can't")),
+            normalize(&tokenize("I can"))
+        );
+    }
+
+    #[test]
+    fn dedup_across_files() {
+        let mut first = file(&["Let's go!", "See you tomorrow."]);
+        let mut second = file(&["Let's go!", "let's go", "See you later."]);
+
+        let mut dedup = Deduplicator::new(opts::Dedup::Exact);
+        dedup.apply(&mut first);
+        dedup.apply(&mut second);
+
+        assert_eq!(dropped(&first), vec![None, None]);
+        assert_eq!(
+            dropped(&second),
+            vec![Some(DropReason::Repeated), None, None]
+        );
+    }
+
+    #[test]
+    fn near_dedup_across_files() {
+        let mut first = file(&["Let's go!", "See you tomorrow."]);
+        let mut second = file(&["Let's go!", "let's go", "See you later."]);
+
+        let mut dedup = Deduplicator::new(opts::Dedup::Near);
+        dedup.apply(&mut first);
+        dedup.apply(&mut second);
+
+        assert_eq!(dropped(&first), vec![None, None]);
+        assert_eq!(
+            dropped(&second),
+            vec![
+                Some(DropReason::Repeated),
+                Some(DropReason::NearDuplicate),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn dedup_skips_dropped_lines() {
+        let mut first = file(&["Let's go!"]);
+        first.lines[0].dropped = Some(DropReason::Comment);
+        let mut second = file(&["Let's go!"]);
+
+        let mut dedup = Deduplicator::new(opts::Dedup::Near);
+        dedup.apply(&mut first);
+        dedup.apply(&mut second);
+
+        assert_eq!(dropped(&first), vec![Some(DropReason::Comment)]);
+        assert_eq!(dropped(&second), vec![None]);
+    }
+
+    #[test]
+    fn adjacent_dedup_keeps_repeats() {
+        let mut first = file(&["Let's go!"]);
+        let mut second = file(&["Let's go!"]);
+
+        let mut dedup = Deduplicator::new(opts::Dedup::Adjacent);
+        dedup.apply(&mut first);
+        dedup.apply(&mut second);
+
+        assert_eq!(dropped(&second), vec![None]);
+    }
+
+    fn train_args(args: &[&str]) -> opts::Train {
+        use structopt::StructOpt;
+        opts::Train::from_iter(Some("train").iter().chain(args))
+    }
+
+    #[test]
+    fn multiple_orders_need_a_binary_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("missing.ass");
+        let output = dir.path().join("model.yaml");
+        let args = train_args(&[
+            "--order",
+            "1,2",
+            "-o",
+            output.to_str().unwrap(),
+            input.to_str().unwrap(),
+        ]);
+
+        // Fails before noticing that there are no input files
+        let error = train(&crate::NoopObserver, args, &mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Config);
+    }
+
+    #[test]
+    fn orders_must_be_positive_and_distinct() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("missing.ass");
+        let output = dir.path().join("model.bin");
+
+        for orders in &["0", "1,0", "2,2", "1,2,1"] {
+            let args = train_args(&[
+                "--order",
+                orders,
+                "--model-format",
+                "binary",
+                "-o",
+                output.to_str().unwrap(),
+                input.to_str().unwrap(),
+            ]);
+            let error = train(&crate::NoopObserver, args, &mut Vec::new()).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Config, "{}", orders);
+        }
+    }
+
+    #[test]
+    fn train_multiple_orders() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("subtitles.ass");
+        std::fs::write(&input, SUBTITLES).unwrap();
+        let output = dir.path().join("model.bin");
+        let args = train_args(&[
+            "--order",
+            "1,2",
+            "--model-format",
+            "binary",
+            "-o",
+            output.to_str().unwrap(),
+            input.to_str().unwrap(),
+        ]);
+
+        train(&crate::NoopObserver, args, &mut Vec::new()).unwrap();
+        match crate::load_model(output.to_str().unwrap()).unwrap() {
+            Model::Backoff(backoff) => assert_eq!(backoff.max_order(), 2),
+            other => panic!("expected a backoff model, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn jobs_dont_change_the_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut inputs = Vec::new();
+        for i in 0..8 {
+            let input = dir.path().join(format!("{}.ass", i));
+            // The second "Hello there" is deduplicated across files, in input order
+            let contents = SUBTITLES.replacen("Hello there", &format!("Line {}", i), 1);
+            std::fs::write(&input, contents).unwrap();
+            inputs.push(input.to_str().unwrap().to_owned());
+        }
+
+        let train_with_jobs = |jobs: &str| {
+            let output = dir.path().join(format!("model-{}.yaml", jobs));
+            let output = output.to_str().unwrap();
+            let mut args = vec!["--jobs", jobs, "-o", output];
+            args.extend(inputs.iter().map(|s| s.as_str()));
+            train(&crate::NoopObserver, train_args(&args), &mut Vec::new()).unwrap();
+            match crate::load_model(output).unwrap() {
+                Model::Chain(chain) => chain.map,
+                other => panic!("expected a single chain, got {:?}", other),
+            }
+        };
+
+        let map = train_with_jobs("1");
+        assert!(!map.is_empty());
+        assert_eq!(train_with_jobs("4"), map);
+    }
 }